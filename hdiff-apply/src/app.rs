@@ -1,5 +1,5 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     env,
     fs::{self},
     io::{self, Write},
@@ -14,7 +14,21 @@ use crossterm::{
 };
 use indicatif::{ProgressBar, ProgressStyle};
 
-use crate::{patchers::PatchManager, update_package::UpdatePackage};
+use crate::{
+    backup,
+    byte_convert::ByteConvert,
+    cancel::CancelToken,
+    disk_space,
+    game_profile::{GameProfile, Selection},
+    hash_cache::HashCache,
+    patchers::{self, PatchContext, PatchManager, PatchOptions},
+    plan,
+    repair::{self, FixMethod},
+    transaction::{self, Recovery, Transaction},
+    types::CustomDiffMap,
+    update_package::UpdatePackage,
+    verify::{self, ExpectedFile, FileState},
+};
 
 pub const RESET: &'static str = "\x1b[0m";
 pub const WHITE: &'static str = "\x1b[1;87m";
@@ -24,13 +38,33 @@ pub const GREEN: &'static str = "\x1b[1;32m";
 
 pub fn print_banner() {
     println!(
-        "{} v{} : Made by nie\n",
+        "{} v{} : Made by nie",
         env!("CARGO_PKG_NAME"),
         env!("CARGO_PKG_VERSION")
     );
 }
 
-pub fn run(game_path: &Path, archives_path: &Path) -> Result<()> {
+/// Finishes the banner with the game in the game directory
+pub fn print_game(profile: &GameProfile, selection: Selection) {
+    match selection {
+        Selection::Detected => println!("Game: {WHITE}{}{RESET}\n", profile.name),
+        Selection::Picked => println!("Game: {WHITE}{}{RESET} (--game)\n", profile.name),
+        Selection::Assumed => println!(
+            "Game: {YELLOW}not detected{RESET}, assuming {} (use --game to pick another)\n",
+            profile.name
+        ),
+    }
+}
+
+pub fn run(
+    game_path: &Path,
+    archives_path: &Path,
+    work_dir: &Path,
+    profile: &GameProfile,
+    selection: Selection,
+    options: &PatchOptions,
+    cancel: &CancelToken,
+) -> Result<()> {
     if !game_path.exists()
         || !archives_path.exists()
         || !game_path.is_dir()
@@ -45,10 +79,55 @@ pub fn run(game_path: &Path, archives_path: &Path) -> Result<()> {
     }
 
     let selected_indices = select_archives(&archives)?;
+    // Past the prompt, the first Ctrl-C cancels at the next safe point instead of exiting
+    let _armed = cancel.arm();
+    let cache = HashCache::load(game_path);
     let total_count = selected_indices.len();
 
     println!("-------------------------------");
 
+    // A damaged archive, or one for another game, should be noticed before hours of extracting and patching
+    let profiles = comparable_profiles(game_path, selection)?;
+    for &idx in &selected_indices {
+        let package = &archives[idx];
+        check_package_game(package, game_path, profile, &profiles)?;
+
+        print!("Testing {}... ", package.name);
+        io::stdout().flush()?;
+
+        package.test(cancel)?;
+        println!("{GREEN}OK{RESET}");
+    }
+    println!();
+
+    print!("Estimating disk usage... ");
+    io::stdout().flush()?;
+    let estimates = selected_indices
+        .iter()
+        .map(|&idx| disk_space::Estimate::of(&archives[idx], game_path))
+        .collect::<Result<Vec<_>>>()?;
+    let needed = disk_space::peak_usage(&estimates, options);
+    println!("{}", ByteConvert::from(needed));
+    if disk_space::same_filesystem(game_path, work_dir) {
+        disk_space::ensure_available(game_path, needed)?;
+    } else {
+        // Staged files are copied over at the commit, so the game needs room for them too
+        disk_space::ensure_available(work_dir, needed)?;
+        disk_space::ensure_available(
+            game_path,
+            disk_space::commit_usage(&estimates, options.atomic),
+        )?;
+    }
+    println!();
+
+    // With --atomic every package is planned on top of the previous one and committed together
+    let mut chain = if options.atomic {
+        Some(Transaction::new(game_path, work_dir)?)
+    } else {
+        None
+    };
+    let mut chained = Vec::new();
+
     for (i, idx) in selected_indices.into_iter().enumerate() {
         let current = i + 1;
         let package = &archives[idx];
@@ -58,12 +137,64 @@ pub fn run(game_path: &Path, archives_path: &Path) -> Result<()> {
         print!("  Extracting archive... ");
         io::stdout().flush()?;
 
-        let temp_extract = HaTemp::new(game_path.join(".ha-extracted"))?;
-        package.extract(&temp_extract)?;
+        let temp_extract = HaTemp::new(work_dir.join(".ha-extracted"))?;
+        cancel.check()?;
+        package.extract(&temp_extract, cancel)?;
         println!("{GREEN}OK{RESET}");
 
-        run_patcher(game_path, &temp_extract)?;
-        merge_into_game(&temp_extract, game_path)?;
+        let mut single = None;
+        let tx = match chain.as_mut() {
+            Some(tx) => {
+                tx.next_step()?;
+                chained.push(package.name.as_str());
+                tx
+            }
+            None => {
+                let tx = single.insert(Transaction::new(game_path, work_dir)?);
+                tx.backup(&package.name, options.backups);
+                tx
+            }
+        };
+
+        let ctx = PatchContext {
+            cache: &cache,
+            options,
+            tx,
+            cancel,
+            profile,
+        };
+        run_patcher(game_path, &temp_extract, &ctx)
+            .with_context(|| format!("Failed to apply '{}'", package.name))?;
+
+        let commit_bar = progress_bar()?;
+        // Once the commit starts it runs to the end, Ctrl-C only stops what comes after it
+        let result = plan_full_files(
+            &temp_extract,
+            &profile.version_file,
+            tx,
+            cancel,
+            &commit_bar,
+        )
+        .and_then(|_| cancel.check())
+        .context("Patch failed - game files remain unchanged!")
+        .and_then(|_| match single {
+            Some(tx) => tx.commit(&cache, &commit_bar),
+            None => Ok(()),
+        });
+        commit_bar.finish_and_clear();
+        result?;
+    }
+
+    if let Some(mut tx) = chain {
+        cancel.check()?;
+        println!("Committing {} package(s)", chained.len());
+        tx.backup(&chained.join(" + "), options.backups);
+
+        let commit_bar = progress_bar()?;
+        let result = tx.commit(&cache, &commit_bar);
+        commit_bar.finish_and_clear();
+        result?;
+        println!();
     }
 
     println!("{WHITE}All {total_count} updates completed successfully!{RESET}");
@@ -71,51 +202,405 @@ pub fn run(game_path: &Path, archives_path: &Path) -> Result<()> {
     Ok(())
 }
 
-fn merge_into_game(from: &Path, to: &Path) -> Result<()> {
-    fn is_patch_metadata(name: &str) -> bool {
-        matches!(
-            name,
-            "hdifffiles.txt" | "hdiffmap.json" | "deletefiles.txt" | "ldiff"
-        ) || name.starts_with("manifest")
+pub fn verify(
+    game_path: &Path,
+    profile: &GameProfile,
+    json_report: Option<&Path>,
+    cancel: &CancelToken,
+) -> Result<()> {
+    if !game_path.is_dir() {
+        bail!("'{}' is not a valid directory", game_path.display());
+    }
+
+    let _armed = cancel.arm();
+    let cache = HashCache::load(game_path);
+    let progress = progress_bar()?;
+    progress.set_message("Verifying files");
+    let result =
+        verify::verify_install(game_path, &profile.version_file, &cache, cancel, &progress);
+    progress.finish_and_clear();
+    let report = result?;
+    cache.save()?;
+
+    if let Some(json_report) = json_report {
+        fs::write(json_report, serde_json::to_string_pretty(&report)?)
+            .with_context(|| format!("Failed to write report to '{}'", json_report.display()))?;
+        println!("Report written to '{}'", json_report.display());
+    }
+
+    if report.failed.is_empty() {
+        println!("{GREEN}All {} files are intact{RESET}", report.checked);
+        return Ok(());
+    }
+
+    for category in ["Missing", "Wrong size", "Wrong hash"] {
+        let files: Vec<_> = report
+            .failed
+            .iter()
+            .filter(|f| f.state.category() == category)
+            .collect();
+        if files.is_empty() {
+            continue;
+        }
+
+        println!("{YELLOW}{category}{RESET} ({}):", files.len());
+        for file in files {
+            match &file.state {
+                FileState::Missing => println!("  {}", file.file),
+                state => println!("  {}: {}", file.file, state),
+            }
+        }
+    }
+    println!();
+
+    bail!(
+        "{} of {} files failed verification",
+        report.failed.len(),
+        report.checked
+    )
+}
+
+pub fn repair(
+    game_path: &Path,
+    archives_path: &Path,
+    work_dir: &Path,
+    profile: &GameProfile,
+    cancel: &CancelToken,
+) -> Result<()> {
+    if !game_path.is_dir() || !archives_path.is_dir() {
+        bail!("'{}' is not a valid directory", game_path.display());
+    }
+
+    let _armed = cancel.arm();
+    let cache = HashCache::load(game_path);
+    let progress = progress_bar()?;
+    progress.set_message("Verifying files");
+    let result =
+        verify::verify_install(game_path, &profile.version_file, &cache, cancel, &progress);
+    progress.finish_and_clear();
+    let report = result?;
+    cache.save()?;
+
+    if report.failed.is_empty() {
+        println!("{GREEN}All {} files are intact{RESET}", report.checked);
+        return Ok(());
+    }
+
+    let archives = UpdatePackage::find(archives_path)?;
+    if archives.is_empty() {
+        bail!("Didn't find any archives in '{}'", archives_path.display())
+    }
+
+    println!(
+        "Found {} broken file(s), looking for fixes in {} package(s)",
+        report.failed.len(),
+        archives.len()
+    );
+
+    let broken = verify::load_pkg_version(game_path, &profile.version_file)?
+        .into_iter()
+        .filter(|entry| report.failed.iter().any(|f| f.file == entry.remote_name))
+        .collect();
+
+    let progress = progress_bar()?;
+    let result = repair::repair(
+        game_path, work_dir, &archives, broken, &cache, &progress, cancel,
+    );
+    progress.finish_and_clear();
+    let outcome = result.context("Repair failed - game files remain unchanged!")?;
+
+    if !outcome.fixed.is_empty() {
+        println!("{GREEN}Repaired{RESET} ({}):", outcome.fixed.len());
+        for fix in &outcome.fixed {
+            match &fix.method {
+                FixMethod::FullFile => println!("  {} (full file from {})", fix.file, fix.package),
+                FixMethod::Patch { source } if source.is_empty() => {
+                    println!("  {} (patched from {})", fix.file, fix.package)
+                }
+                FixMethod::Patch { source } => {
+                    println!(
+                        "  {} (patched from {} using {})",
+                        fix.file, source, fix.package
+                    )
+                }
+            }
+        }
+    }
+
+    if !outcome.unresolved.is_empty() {
+        println!(
+            "{YELLOW}Could not repair{RESET} ({}):",
+            outcome.unresolved.len()
+        );
+        for file in &outcome.unresolved {
+            println!("  {}", file);
+        }
+        println!();
+
+        bail!(
+            "{} file(s) couldn't be repaired with the available packages",
+            outcome.unresolved.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Prints what applying the selected packages would do without touching the game
+pub fn dry_run(
+    game_path: &Path,
+    archives_path: &Path,
+    profile: &GameProfile,
+    selection: Selection,
+    options: &PatchOptions,
+    json_report: Option<&Path>,
+) -> Result<()> {
+    if !game_path.is_dir() || !archives_path.is_dir() {
+        bail!("'{}' is not a valid directory", game_path.display());
+    }
+
+    let archives = UpdatePackage::find(archives_path)?;
+    if archives.is_empty() {
+        bail!("Didn't find any archives in '{}'", archives_path.display())
+    }
+
+    let selected_indices = select_archives(&archives)?;
+    println!("-------------------------------");
+
+    let profiles = comparable_profiles(game_path, selection)?;
+    let mut packages = Vec::new();
+    let mut estimates = Vec::new();
+    for &idx in &selected_indices {
+        let package = &archives[idx];
+        check_package_game(package, game_path, profile, &profiles)?;
+        packages.push(
+            plan::plan_package(package, game_path)
+                .with_context(|| format!("Failed to plan '{}'", package.name))?,
+        );
+        estimates.push(disk_space::Estimate::of(package, game_path)?);
+    }
+
+    let update_plan = plan::UpdatePlan {
+        packages,
+        disk_usage: disk_space::peak_usage(&estimates, options),
+    };
+
+    for package in &update_plan.packages {
+        println!("{WHITE}{}{RESET} ({})", package.package, package.patcher);
+        for (label, files) in [
+            ("Patched", &package.patched),
+            ("Added", &package.added),
+            ("Replaced", &package.replaced),
+            ("Deleted", &package.deleted),
+        ] {
+            if files.is_empty() {
+                continue;
+            }
+
+            let total: u64 = files.iter().map(|f| f.size).sum();
+            println!(
+                "  {YELLOW}{label}{RESET} ({}, {}):",
+                files.len(),
+                ByteConvert::from(total)
+            );
+            for file in files {
+                println!("    {} ({})", file.file, ByteConvert::from(file.size));
+            }
+        }
+        println!();
+    }
+
+    println!(
+        "Estimated peak disk usage: {}",
+        ByteConvert::from(update_plan.disk_usage)
+    );
+
+    if let Some(json_report) = json_report {
+        fs::write(json_report, serde_json::to_string_pretty(&update_plan)?)
+            .with_context(|| format!("Failed to write plan to '{}'", json_report.display()))?;
+        println!("Plan written to '{}'", json_report.display());
+    }
+
+    println!("{GREEN}Dry run, nothing was changed{RESET}");
+
+    Ok(())
+}
+
+pub fn rollback(game_path: &Path, cancel: &CancelToken) -> Result<()> {
+    if !game_path.is_dir() {
+        bail!("'{}' is not a valid directory", game_path.display());
+    }
+
+    let _armed = cancel.arm();
+    let cache = HashCache::load(game_path);
+    let progress = progress_bar()?;
+    let result = backup::rollback(game_path, &cache, cancel, &progress);
+    progress.finish_and_clear();
+    let package = result.context("Rollback failed")?;
+
+    println!("{GREEN}Rolled back{RESET} {package}");
+
+    Ok(())
+}
+
+/// Plans moving every file the package ships in full into the game.
+/// Files the package's own `pkg_version` lists have to match it first
+fn plan_full_files(
+    extracted: &Path,
+    version_file: &str,
+    tx: &Transaction,
+    cancel: &CancelToken,
+    progress: &ProgressBar,
+) -> Result<()> {
+    let mut files = Vec::new();
+    collect_full_files(extracted, Path::new(""), &mut files)?;
+
+    let expected: HashMap<String, CustomDiffMap> = if extracted.join(version_file).is_file() {
+        verify::load_pkg_version(extracted, version_file)?
+            .into_iter()
+            .map(|entry| (entry.remote_name.clone(), entry))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+
+    let checked: Vec<ExpectedFile> = files
+        .iter()
+        .filter_map(|name| expected.get(name))
+        .map(|entry| ExpectedFile {
+            name: &entry.remote_name,
+            size: entry.file_size,
+            md5: &entry.md5,
+        })
+        .collect();
+
+    progress.set_message("Checking new files");
+    verify::ensure_files(
+        extracted,
+        &checked,
+        None,
+        cancel,
+        progress,
+        "file(s) in the package don't match its pkg_version",
+    )?;
+
+    // Staged so the files outlive the extracted package when several packages are chained
+    for name in files {
+        let staged = tx.staging_dir().join(&name);
+        if staged.exists() {
+            bail!(
+                "'{}' is both patched and shipped in full by the package",
+                name
+            );
+        }
+        if let Some(parent) = staged.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(extracted.join(&name), &staged)?;
+
+        let md5 = expected.get(&name).map_or("", |entry| entry.md5.as_str());
+        tx.replace(staged, &name, md5);
     }
 
+    Ok(())
+}
+
+fn collect_full_files(from: &Path, rel: &Path, out: &mut Vec<String>) -> Result<()> {
     for entry in fs::read_dir(from)? {
         let entry = entry?;
         let name = entry.file_name();
 
-        if is_patch_metadata(&name.to_string_lossy()) {
+        if patchers::is_patch_metadata(&name.to_string_lossy()) {
             continue;
         }
 
-        let src = entry.path();
-        let dst = to.join(&name);
+        let rel = rel.join(&name);
 
         if entry.file_type()?.is_dir() {
-            fs::create_dir_all(&dst)?;
-            merge_into_game(&src, &dst)?;
+            collect_full_files(&entry.path(), &rel, out)?;
         } else {
-            fs::rename(&src, &dst)?;
+            out.push(rel.to_string_lossy().replace('\\', "/"));
         }
     }
 
     Ok(())
 }
 
-fn run_patcher(game_path: &Path, patch_path: &Path) -> Result<()> {
+/// The profiles a package is checked against, a game that was only assumed can't tell whose package is wrong
+fn comparable_profiles(game_path: &Path, selection: Selection) -> Result<Vec<GameProfile>> {
+    match selection {
+        Selection::Assumed => Ok(Vec::new()),
+        Selection::Detected | Selection::Picked => GameProfile::load_all(game_path),
+    }
+}
+
+/// Refuses a package whose files are laid out for another game than the one in `game_path`
+fn check_package_game(
+    package: &UpdatePackage,
+    game_path: &Path,
+    profile: &GameProfile,
+    profiles: &[GameProfile],
+) -> Result<()> {
+    let entries = package.list()?;
+    let mut names: Vec<String> = entries.iter().map(|entry| entry.path.clone()).collect();
+
+    // Same manifest as `PatchManager::create_patcher` picks, its assets are the actual game files
+    if let Some(manifest) = entries
+        .iter()
+        .filter(|entry| !entry.is_dir && !entry.path.contains('/'))
+        .find(|entry| entry.path.starts_with("manifest"))
+    {
+        let data = package.read_file(&manifest.path)?;
+        names.extend(
+            PatchManager::manifest_files(&data)
+                .with_context(|| format!("Failed to read '{}'", manifest.path))?,
+        );
+    }
+
+    if let Some(owner) = profile.foreign_owner(profiles, &names) {
+        bail!(
+            "'{}' is an update for {}, but '{}' is {}",
+            package.name,
+            owner.name,
+            game_path.display(),
+            profile.name
+        );
+    }
+
+    Ok(())
+}
+
+/// Finishes or rolls back a commit an earlier run didn't get to complete
+pub fn recover(game_path: &Path, work_dir: &Path) -> Result<()> {
+    match transaction::recover(game_path, work_dir)? {
+        Some(Recovery::Finished(count)) => {
+            println!("{YELLOW}Finished an interrupted update{RESET} ({count} file operation(s))\n")
+        }
+        Some(Recovery::RolledBack(count)) => println!(
+            "{YELLOW}Rolled back an interrupted update{RESET} ({count} file operation(s))\n"
+        ),
+        None => {}
+    }
+
+    Ok(())
+}
+
+fn run_patcher(game_path: &Path, patch_path: &Path, ctx: &PatchContext) -> Result<()> {
     let patcher = PatchManager::new(game_path, patch_path)?;
 
-    let patch_bar = ProgressBar::new(0);
-    patch_bar.set_style(
-        ProgressStyle::default_bar()
-            .template("  {msg:<20} [{bar:40.cyan/blue}] {pos:>4}/{len:4} ({percent}%)")?
-            .progress_chars("##-"),
-    );
+    let patch_bar = progress_bar()?;
 
-    let result = patcher.patch(&patch_bar);
+    let result = patcher.patch(ctx, &patch_bar);
 
     patch_bar.finish_and_clear();
 
-    result.context("Patch failed - game files remain unchanged!")?;
+    if ctx.options.low_disk {
+        result.context(
+            "Patch failed - files already replaced were kept, run the update again to finish it",
+        )?;
+    } else {
+        result.context("Patch failed - game files remain unchanged!")?;
+    }
 
     println!("  Patching complete using {}", patcher.patcher_name());
     println!();
@@ -123,6 +608,16 @@ fn run_patcher(game_path: &Path, patch_path: &Path) -> Result<()> {
     Ok(())
 }
 
+fn progress_bar() -> Result<ProgressBar> {
+    let bar = ProgressBar::new(0);
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("  {msg:<20} [{bar:40.cyan/blue}] {pos:>4}/{len:4} ({percent}%)")?
+            .progress_chars("##-"),
+    );
+    Ok(bar)
+}
+
 fn select_archives(archives: &[UpdatePackage]) -> Result<Vec<usize>> {
     if archives.len() == 1 {
         return Ok(vec![0]);
@@ -240,24 +735,34 @@ fn read_line() -> Result<String> {
     Ok(input)
 }
 
-pub struct HaTemp(PathBuf);
+pub struct HaTemp {
+    path: PathBuf,
+    keep: bool,
+}
 
 impl HaTemp {
     pub fn new(path: PathBuf) -> Result<Self> {
         fs::create_dir_all(&path)?;
-        Ok(Self(path))
+        Ok(Self { path, keep: false })
+    }
+
+    /// Leaves the directory behind when dropped, something outside still needs what's in it
+    pub fn keep(&mut self) {
+        self.keep = true;
     }
 }
 
 impl Drop for HaTemp {
     fn drop(&mut self) {
-        let _ = fs::remove_dir_all(&self.0);
+        if !self.keep {
+            let _ = fs::remove_dir_all(&self.path);
+        }
     }
 }
 
 impl Deref for HaTemp {
     type Target = Path;
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.path
     }
 }
@@ -0,0 +1,147 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cancel::CancelToken,
+    hash_cache::HashCache,
+    transaction::{self, Operation, Transaction},
+};
+
+const BACKUP_DIR: &str = ".ha-backups";
+const BACKUP_FILE: &str = "backup.json";
+
+/// What a committed package changed, the files it replaced or deleted sit next to this in the backup directory
+#[derive(Serialize, Deserialize)]
+struct Backup {
+    package: String,
+    operations: Vec<Operation>,
+}
+
+/// Turns the originals a commit moved aside into the newest backup and drops backups beyond `keep`
+pub fn store(
+    game_path: &Path,
+    originals: &Path,
+    package: &str,
+    operations: Vec<Operation>,
+    keep: usize,
+) -> Result<()> {
+    fs::create_dir_all(originals)?;
+    let backup = Backup {
+        package: package.to_string(),
+        operations,
+    };
+    fs::write(originals.join(BACKUP_FILE), serde_json::to_vec(&backup)?)?;
+
+    let backups = list(game_path)?;
+    let next = backups.last().map_or(1, |(number, _)| number + 1);
+
+    let backup_dir = game_path.join(BACKUP_DIR);
+    fs::create_dir_all(&backup_dir)?;
+    let path = backup_dir.join(format!("{next:04}"));
+    fs::rename(originals, &path)
+        .with_context(|| format!("Failed to store backup '{}'", path.display()))?;
+
+    let count = backups.len() + 1;
+    for (_, old) in backups.iter().take(count.saturating_sub(keep)) {
+        let _ = fs::remove_dir_all(old);
+    }
+
+    Ok(())
+}
+
+/// Restores the game to what it was before the newest backed up package and returns that package's name
+pub fn rollback(
+    game_path: &Path,
+    cache: &HashCache,
+    cancel: &CancelToken,
+    progress: &ProgressBar,
+) -> Result<String> {
+    let Some((_, path)) = list(game_path)?.pop() else {
+        bail!("No backups found in '{}'", game_path.display());
+    };
+
+    let data = fs::read_to_string(path.join(BACKUP_FILE))
+        .with_context(|| format!("Failed to read backup '{}'", path.display()))?;
+    let backup: Backup = serde_json::from_str(&data)
+        .with_context(|| format!("Failed to parse backup '{}'", path.display()))?;
+
+    // Once the backup is in use it mustn't be picked up again, even if this gets interrupted
+    let restoring = path.with_extension("restoring");
+    fs::rename(&path, &restoring)?;
+
+    // Everything restored already sits in the game directory
+    let mut tx = Transaction::new(game_path, game_path)?;
+    tx.keep_backups();
+    for operation in &backup.operations {
+        cancel.check()?;
+        match operation {
+            Operation::Replace {
+                target,
+                existed: false,
+                ..
+            } => tx.delete(target),
+            Operation::Replace { target, .. } | Operation::Delete { target } => {
+                let original = restoring.join(target);
+                // A deleted file that wasn't there to begin with has nothing to restore
+                if original.exists() {
+                    tx.replace(original, target, "");
+                }
+            }
+        }
+    }
+    // Once the commit starts it runs to the end
+    if let Err(e) = cancel.check().and_then(|_| tx.commit(cache, progress)) {
+        // Without a journal nothing was moved yet and the backup is still whole, so it's put back.
+        // Otherwise the journal finishes or rolls back the restore and the backup is used up either way
+        if !transaction::has_journal(game_path) {
+            fs::rename(&restoring, &path)
+                .with_context(|| format!("Failed to put back backup '{}'", path.display()))?;
+        }
+        return Err(e);
+    }
+
+    let _ = fs::remove_dir_all(&restoring);
+    Ok(backup.package)
+}
+
+/// Drops every backup, for a commit that changes the game without storing one of its own
+pub fn discard(game_path: &Path) -> Result<()> {
+    let backup_dir = game_path.join(BACKUP_DIR);
+    match fs::remove_dir_all(&backup_dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e)
+            .with_context(|| format!("Failed to remove the backups in '{}'", backup_dir.display())),
+        _ => Ok(()),
+    }
+}
+
+/// Backups ordered from oldest to newest, leftovers of an interrupted rollback are removed
+fn list(game_path: &Path) -> Result<Vec<(u32, PathBuf)>> {
+    let backup_dir = game_path.join(BACKUP_DIR);
+    if !backup_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(&backup_dir)? {
+        let path = entry?.path();
+        let number = path
+            .file_name()
+            .and_then(|name| name.to_str()?.parse().ok());
+
+        match number {
+            Some(number) if path.join(BACKUP_FILE).is_file() => backups.push((number, path)),
+            _ => {
+                let _ = fs::remove_dir_all(&path);
+            }
+        }
+    }
+
+    backups.sort();
+    Ok(backups)
+}
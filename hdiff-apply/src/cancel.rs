@@ -0,0 +1,78 @@
+use std::{
+    process,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+};
+
+use anyhow::{Context, Result, bail};
+
+use crate::app::{RESET, YELLOW};
+
+/// Set by Ctrl-C so long running work can stop at a point where nothing is left half done
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<State>);
+
+#[derive(Default)]
+struct State {
+    cancelled: AtomicBool,
+    /// How many pieces of cancellable work are running, with none a Ctrl-C exits right away
+    armed: AtomicUsize,
+}
+
+/// Keeps Ctrl-C cancelling instead of exiting until it's dropped, see [`CancelToken::arm`]
+pub struct Armed(CancelToken);
+
+impl Drop for Armed {
+    fn drop(&mut self) {
+        self.0.0.armed.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl CancelToken {
+    /// Installs the Ctrl-C handler, a second Ctrl-C exits right away and leaves the rest to the journal.
+    /// Outside of armed work, e.g. at a prompt, the first Ctrl-C already exits
+    pub fn install() -> Result<Self> {
+        let token = Self::default();
+        let state = token.0.clone();
+
+        ctrlc::set_handler(move || {
+            if state.armed.load(Ordering::Relaxed) == 0 {
+                eprintln!();
+                process::exit(130);
+            }
+            if state.cancelled.swap(true, Ordering::Relaxed) {
+                eprintln!(
+                    "\n{YELLOW}Exiting now, run hdiff-apply again to finish or roll back an interrupted update{RESET}"
+                );
+                process::exit(130);
+            }
+            eprintln!("\n{YELLOW}Cancelling, press Ctrl-C again to exit right away{RESET}");
+        })
+        .context("Failed to install the Ctrl-C handler")?;
+
+        Ok(token)
+    }
+
+    /// Marks work that checks the token as running, for as long as the guard lives
+    pub fn arm(&self) -> Armed {
+        self.0.armed.fetch_add(1, Ordering::Relaxed);
+        Armed(self.clone())
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            bail!("Cancelled");
+        }
+        Ok(())
+    }
+
+    pub fn flag(&self) -> &AtomicBool {
+        &self.0.cancelled
+    }
+}
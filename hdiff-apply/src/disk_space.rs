@@ -0,0 +1,135 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+
+use crate::{
+    byte_convert::ByteConvert,
+    patchers::{self, PatchManager, PatchOptions},
+    update_package::UpdatePackage,
+};
+
+/// Disk space one package takes up while it's applied
+#[derive(Debug, Default)]
+pub struct Estimate {
+    /// Everything in the archive once extracted
+    pub extracted: u64,
+    /// Files shipped in full, they stay staged until the commit
+    pub full_files: u64,
+    /// hdiff files sliced out of ldiff chunks
+    pub generated: u64,
+    /// Patched files waiting in staging
+    pub patched: u64,
+    /// The largest single patched file, all low disk mode stages at once
+    pub largest: u64,
+}
+
+impl Estimate {
+    /// Works out what the package needs from the archive listing and its patch metadata, without extracting it
+    pub fn of(package: &UpdatePackage, game_path: &Path) -> Result<Self> {
+        let entries = package.list()?;
+        let mut estimate = Self::default();
+
+        for entry in entries.iter().filter(|entry| !entry.is_dir) {
+            estimate.extracted += entry.size;
+
+            let is_metadata = entry.path.split('/').any(patchers::is_patch_metadata);
+            if !is_metadata && !entry.path.ends_with(".hdiff") {
+                estimate.full_files += entry.size;
+            }
+        }
+
+        // Same order as `PatchManager::create_patcher`
+        let metadata = entries
+            .iter()
+            .filter(|entry| !entry.is_dir && !entry.path.contains('/'))
+            .find(|entry| entry.path.starts_with("manifest"))
+            .or_else(|| {
+                entries
+                    .iter()
+                    .find(|entry| matches!(entry.path.as_str(), "hdifffiles.txt" | "hdiffmap.json"))
+            });
+
+        if let Some(metadata) = metadata {
+            let data = package.read_file(&metadata.path)?;
+            (estimate.generated, estimate.patched, estimate.largest) =
+                PatchManager::estimate_output(game_path, &metadata.path, &data)
+                    .with_context(|| format!("Failed to read '{}'", metadata.path))?;
+        }
+
+        Ok(estimate)
+    }
+
+    fn peak(&self, low_disk: bool) -> u64 {
+        if low_disk {
+            // Chunks are sliced one hdiff at a time, each file moves into the game once it's patched
+            // and the file it replaced is dropped
+            self.extracted + self.largest
+        } else {
+            self.extracted + self.generated + self.patched
+        }
+    }
+}
+
+/// Peak disk usage of applying `estimates` in order, with `atomic` every package's output stays staged until the end
+pub fn peak_usage(estimates: &[Estimate], options: &PatchOptions) -> u64 {
+    let mut staged = 0;
+    let mut peak = 0;
+
+    for estimate in estimates {
+        peak = peak.max(staged + estimate.peak(options.low_disk));
+        if options.atomic {
+            staged += estimate.full_files + estimate.patched;
+        }
+    }
+
+    peak
+}
+
+/// What the commit copies into the game when staging is on another filesystem
+pub fn commit_usage(estimates: &[Estimate], atomic: bool) -> u64 {
+    let staged = estimates
+        .iter()
+        .map(|estimate| estimate.full_files + estimate.patched);
+
+    if atomic {
+        staged.sum()
+    } else {
+        staged.max().unwrap_or(0)
+    }
+}
+
+#[cfg(unix)]
+pub fn same_filesystem(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (a.metadata(), b.metadata()) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn same_filesystem(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a.components().next() == b.components().next(),
+        _ => false,
+    }
+}
+
+/// Refuses to start when the filesystem holding `path` can't fit `needed` bytes
+pub fn ensure_available(path: &Path, needed: u64) -> Result<()> {
+    let available = fs4::available_space(path)
+        .with_context(|| format!("Failed to read the free disk space of '{}'", path.display()))?;
+
+    if available < needed {
+        bail!(
+            "Not enough disk space on '{}': the update needs about {} but only {} is free ({} short)",
+            path.display(),
+            ByteConvert::from(needed),
+            ByteConvert::from(available),
+            ByteConvert::from(needed - available)
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,208 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+use crate::safe_path;
+
+/// Profiles of games the built-in list doesn't cover, or overrides of built-in ones, read from the game directory
+pub const PROFILES_FILE: &str = "hdiff-apply.toml";
+
+/// Used when no profile is picked and none is detected, it's the game hdiff-apply was first written for
+const DEFAULT_PROFILE: &str = "hsr";
+
+/// Where a game keeps its files and which of them hdiff-apply has to stay away from
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GameProfile {
+    /// Short name to pick the profile with `--game`
+    pub id: String,
+    pub name: String,
+    /// The game's executable in the game directory
+    pub executable: String,
+    /// Directories the ldiff cleanup looks through for files no update knows about
+    pub data_dirs: Vec<String>,
+    /// Directories inside the data directories the game writes to itself, they're never swept
+    #[serde(default)]
+    pub protected_dirs: Vec<String>,
+    /// Lists every game file with its md5 and size, in the format of `pkg_version`
+    #[serde(default = "default_version_file")]
+    pub version_file: String,
+}
+
+/// How the profile for a game directory was chosen
+#[derive(Debug, Clone, Copy)]
+pub enum Selection {
+    Detected,
+    /// Named with `--game`
+    Picked,
+    /// Nothing was detected, so it's [`DEFAULT_PROFILE`]
+    Assumed,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfilesFile {
+    #[serde(default, rename = "profile")]
+    profiles: Vec<GameProfile>,
+}
+
+fn default_version_file() -> String {
+    "pkg_version".to_string()
+}
+
+impl GameProfile {
+    fn new(id: &str, name: &str, executable: &str, data_dir: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            name: name.to_string(),
+            executable: executable.to_string(),
+            data_dirs: vec![data_dir.to_string()],
+            protected_dirs: vec!["Persistent".to_string()],
+            version_file: default_version_file(),
+        }
+    }
+
+    pub fn built_in() -> Vec<Self> {
+        vec![
+            Self::new("hsr", "Honkai: Star Rail", "StarRail.exe", "StarRail_Data"),
+            Self::new(
+                "genshin",
+                "Genshin Impact",
+                "GenshinImpact.exe",
+                "GenshinImpact_Data",
+            ),
+            Self::new(
+                "yuanshen",
+                "Genshin Impact (CN)",
+                "YuanShen.exe",
+                "YuanShen_Data",
+            ),
+            Self::new(
+                "zzz",
+                "Zenless Zone Zero",
+                "ZenlessZoneZero.exe",
+                "ZenlessZoneZero_Data",
+            ),
+            Self::new("hi3", "Honkai Impact 3rd", "BH3.exe", "BH3_Data"),
+        ]
+    }
+
+    /// The built-in profiles plus the ones in the game's `hdiff-apply.toml`,
+    /// a profile there replaces the built-in one with the same id
+    pub fn load_all(game_path: &Path) -> Result<Vec<Self>> {
+        let mut profiles = Self::built_in();
+
+        let path = game_path.join(PROFILES_FILE);
+        if !path.is_file() {
+            return Ok(profiles);
+        }
+
+        let data = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read '{}'", path.display()))?;
+        let file: ProfilesFile = toml::from_str(&data)
+            .with_context(|| format!("Failed to parse '{}'", path.display()))?;
+
+        for profile in file.profiles {
+            profile.check().with_context(|| {
+                format!("Invalid profile '{}' in '{}'", profile.id, path.display())
+            })?;
+
+            match profiles
+                .iter_mut()
+                .find(|p| p.id.eq_ignore_ascii_case(&profile.id))
+            {
+                Some(existing) => *existing = profile,
+                None => profiles.push(profile),
+            }
+        }
+
+        Ok(profiles)
+    }
+
+    /// Looks up the profile with the given id, see [`GameProfile::load_all`]
+    pub fn find(game_path: &Path, id: &str) -> Result<Self> {
+        let profiles = Self::load_all(game_path)?;
+        let known = profiles
+            .iter()
+            .map(|p| format!("{} ({})", p.id, p.name))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        match profiles
+            .iter()
+            .find(|profile| profile.id.eq_ignore_ascii_case(id))
+        {
+            Some(profile) => Ok(profile.clone()),
+            None => bail!("Unknown game '{}', expected one of: {}", id, known),
+        }
+    }
+
+    /// The profile with id `id` if one is given, otherwise the one detected in `game_path`
+    pub fn select(game_path: &Path, id: Option<&str>) -> Result<(Self, Selection)> {
+        if let Some(id) = id {
+            return Ok((Self::find(game_path, id)?, Selection::Picked));
+        }
+
+        match Self::detect(game_path)? {
+            Some(profile) => Ok((profile, Selection::Detected)),
+            None => Ok((Self::find(game_path, DEFAULT_PROFILE)?, Selection::Assumed)),
+        }
+    }
+
+    /// Picks the profile of the game in `game_path` by its executable, or by its data directory when
+    /// the executable is missing. Returns `None` when it doesn't look like any known game
+    pub fn detect(game_path: &Path) -> Result<Option<Self>> {
+        let profiles = Self::load_all(game_path)?;
+
+        let by_executable = profiles
+            .iter()
+            .find(|profile| game_path.join(&profile.executable).is_file());
+        let by_data_dir = || {
+            profiles.iter().find(|profile| {
+                profile
+                    .data_dirs
+                    .iter()
+                    .any(|dir| game_path.join(dir).is_dir())
+            })
+        };
+
+        Ok(by_executable.or_else(by_data_dir).cloned())
+    }
+
+    /// The game `names` clearly belong to when it isn't this one: some of them are in another
+    /// profile's data directories or are its executable, and none of them are this profile's
+    pub fn foreign_owner<'a>(&self, profiles: &'a [Self], names: &[String]) -> Option<&'a Self> {
+        if names.iter().any(|name| self.owns(name)) {
+            return None;
+        }
+
+        profiles
+            .iter()
+            .filter(|profile| !profile.id.eq_ignore_ascii_case(&self.id))
+            .find(|profile| names.iter().any(|name| profile.owns(name)))
+    }
+
+    fn owns(&self, name: &str) -> bool {
+        let name = name.replace('\\', "/");
+        let path = Path::new(&name);
+
+        path == Path::new(&self.executable)
+            || self.data_dirs.iter().any(|dir| path.starts_with(dir))
+    }
+
+    /// Every path in a profile stays inside the game directory, just like the ones in patch metadata
+    fn check(&self) -> Result<()> {
+        if self.id.is_empty() {
+            bail!("the id is empty");
+        }
+
+        safe_path::check(&self.executable, "profile")?;
+        safe_path::check(&self.version_file, "profile")?;
+        for dir in self.data_dirs.iter().chain(&self.protected_dirs) {
+            safe_path::check(dir, "profile")?;
+        }
+
+        Ok(())
+    }
+}
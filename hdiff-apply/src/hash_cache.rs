@@ -0,0 +1,122 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::verify;
+
+const CACHE_FILE: &str = ".ha-hashcache.json";
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CachedHash {
+    size: u64,
+    mtime: u64,
+    md5: String,
+}
+
+/// MD5s of game files keyed by their path relative to the game directory.
+/// An entry is only trusted while the file's size and mtime are unchanged
+pub struct HashCache {
+    root: PathBuf,
+    entries: Mutex<HashMap<String, CachedHash>>,
+}
+
+impl HashCache {
+    /// Loads the cache from the game directory, a missing or unreadable cache just starts empty
+    pub fn load(game_path: &Path) -> Self {
+        let entries = fs::read_to_string(game_path.join(CACHE_FILE))
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default();
+
+        Self {
+            root: game_path.to_path_buf(),
+            entries: Mutex::new(entries),
+        }
+    }
+
+    pub fn md5(&self, name: &str) -> Result<String> {
+        let path = self.root.join(name);
+        let (size, mtime) = Self::stamp(&path)?;
+
+        if let Some(cached) = self.entries.lock().unwrap().get(name)
+            && cached.size == size
+            && cached.mtime == mtime
+        {
+            return Ok(cached.md5.clone());
+        }
+
+        let md5 = verify::file_md5(&path)?;
+        self.entries.lock().unwrap().insert(
+            name.to_string(),
+            CachedHash {
+                size,
+                mtime,
+                md5: md5.clone(),
+            },
+        );
+
+        Ok(md5)
+    }
+
+    /// Records the md5 of a file that was just written and verified, an empty md5 drops the entry
+    pub fn record(&self, name: &str, md5: &str) -> Result<()> {
+        if md5.is_empty() {
+            self.entries.lock().unwrap().remove(name);
+            return Ok(());
+        }
+
+        let (size, mtime) = Self::stamp(&self.root.join(name))?;
+        self.entries.lock().unwrap().insert(
+            name.to_string(),
+            CachedHash {
+                size,
+                mtime,
+                md5: md5.to_ascii_lowercase(),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Writes the cache back, dropping entries for files that were removed or changed since
+    pub fn save(&self) -> Result<()> {
+        let entries: HashMap<_, _> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, cached)| {
+                Self::stamp(&self.root.join(name))
+                    .map(|(size, mtime)| cached.size == size && cached.mtime == mtime)
+                    .unwrap_or(false)
+            })
+            .map(|(name, cached)| (name.clone(), cached.clone()))
+            .collect();
+
+        let path = self.root.join(CACHE_FILE);
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, serde_json::to_vec(&entries)?)
+            .and_then(|_| fs::rename(&temp_path, &path))
+            .with_context(|| format!("Failed to write hash cache '{}'", path.display()))
+    }
+
+    fn stamp(path: &Path) -> Result<(u64, u64)> {
+        let metadata = path
+            .metadata()
+            .with_context(|| format!("Failed to read metadata of '{}'", path.display()))?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+
+        Ok((metadata.len(), mtime))
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    path::Path,
+};
+
+use anyhow::{Context, Result, bail};
+
+const LOCK_FILE: &str = ".ha-lock";
+
+/// Keeps other hdiff-apply processes out of the game directory for as long as it's alive.
+/// The lock is advisory and released by the OS when the process exits, so the file itself is left behind
+pub struct GameLock(#[allow(unused)] File);
+
+impl GameLock {
+    pub fn acquire(game_path: &Path) -> Result<Self> {
+        let path = game_path.join(LOCK_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open lock file '{}'", path.display()))?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(Self(file)),
+            Err(TryLockError::WouldBlock) => bail!(
+                "Another hdiff-apply is already working on '{}'",
+                game_path.display()
+            ),
+            Err(TryLockError::Error(e)) => {
+                Err(e).with_context(|| format!("Failed to lock '{}'", path.display()))
+            }
+        }
+    }
+}
+
+/// Refuses to go on while a program from the game directory is running, it could hold files open or write to them.
+/// `executable` is the game's own executable from its profile
+pub fn ensure_game_not_running(game_path: &Path, executable: &str) -> Result<()> {
+    if let Some((pid, exe)) = find_running(game_path, executable)? {
+        bail!(
+            "'{}' is running from the game directory (pid {}), close it first",
+            exe,
+            pid
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn find_running(game_path: &Path, executable: &str) -> Result<Option<(u32, String)>> {
+    use std::{fs, path::PathBuf};
+
+    let game_path = game_path.canonicalize()?;
+    let executable = Path::new(executable).file_name();
+    let own_pid = std::process::id();
+
+    for entry in fs::read_dir("/proc")? {
+        let Ok(pid) = entry?.file_name().to_string_lossy().parse::<u32>() else {
+            continue;
+        };
+        if pid == own_pid {
+            continue;
+        }
+
+        // Processes of other users or ones that just exited can't be read, they're skipped
+        let proc_path = PathBuf::from("/proc").join(pid.to_string());
+        if let Ok(exe) = fs::read_link(proc_path.join("exe"))
+            && exe.starts_with(&game_path)
+        {
+            return Ok(Some((pid, exe.display().to_string())));
+        }
+
+        // Games started through Wine or Proton run as the Wine loader, their executable only shows up
+        // in the command line and usually with a Windows path. When the path doesn't lead into the
+        // game directory, the game's executable running from there still counts
+        if let Ok(cmdline) = fs::read(proc_path.join("cmdline")) {
+            let cwd = fs::read_link(proc_path.join("cwd")).ok();
+            let exe = cmdline
+                .split(|&b| b == 0)
+                .map(String::from_utf8_lossy)
+                .find(|arg| {
+                    if !arg.to_ascii_lowercase().ends_with(".exe") {
+                        return false;
+                    }
+
+                    let path = PathBuf::from(unix_path(arg));
+                    let is_game = match (path.file_name(), executable) {
+                        (Some(name), Some(executable)) => name.eq_ignore_ascii_case(executable),
+                        _ => false,
+                    };
+                    path.starts_with(&game_path)
+                        || (is_game && cwd.as_ref().is_some_and(|cwd| cwd.starts_with(&game_path)))
+                });
+            if let Some(exe) = exe {
+                return Ok(Some((pid, exe.into_owned())));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Turns a path Wine passes on into a Unix one, `Z:` is where Wine maps the Unix root
+#[cfg(target_os = "linux")]
+fn unix_path(arg: &str) -> String {
+    let path = arg.replace('\\', "/");
+    match path.get(..2) {
+        Some(drive) if drive.eq_ignore_ascii_case("z:") => path[2..].to_string(),
+        _ => path,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn find_running(_game_path: &Path, _executable: &str) -> Result<Option<(u32, String)>> {
+    Ok(None)
+}
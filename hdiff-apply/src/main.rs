@@ -1,7 +1,7 @@
 #![feature(try_blocks)]
 
 use std::{
-    env,
+    env, fs,
     io::{self, Write},
     path::PathBuf,
     process,
@@ -9,48 +9,135 @@ use std::{
 
 use anyhow::{Context, Result, anyhow};
 use app::{RED, RESET};
+use cancel::CancelToken;
+use game_profile::GameProfile;
+use patchers::PatchOptions;
 use seven_zip::SevenZip;
 
 mod app;
+mod backup;
 mod byte_convert;
+mod cancel;
+mod disk_space;
+mod game_profile;
+mod hash_cache;
+mod lock;
 mod patchers;
+mod plan;
+mod repair;
+mod safe_path;
 mod sophon_proto;
+mod transaction;
 mod types;
 mod update_package;
+mod verify;
 
 const USAGE: &'static str = r"Usage:
-    hdiff-apply.exe [options]
+    hdiff-apply.exe [command] [options]
+
+Commands:
+    update                      Apply patch archives to the game (default)
+    verify                      Check the game files against pkg_version
+    repair                      Rebuild files that fail verification from the patch archives
+    rollback                    Restore the game to what it was before the last applied package
 
 Options:
     -g, --game-path <DIR>       Game installation directory (default: current working directory)
     -a, --archives-path <DIR>   Directory containing patch archives (default: --game-path)
+    -w, --work-dir <DIR>        Directory for extracted and staged files (default: --game-path)
+    --game <ID>                 Game profile to use: hsr, genshin, yuanshen, zzz, hi3 or one from hdiff-apply.toml (default: detected)
+    -j, --json <FILE>           Write the verify report or dry-run plan as JSON to FILE
+    --dry-run                   Print what the update would do without changing anything
+    --on-modified <POLICY>      What to do with locally modified game files: abort, skip or overwrite (default: abort)
+    --atomic                    Only change the game once every selected package was applied
+    --keep-mtime                Keep the modification time of patched files
+    --low-disk                  Patch and replace one file at a time, a failed package keeps the files it already replaced. Can't be combined with --atomic or --backups
+    --backups <COUNT>           Keep backups of the last COUNT packages for rollback (default: 0, disabled)
     -h, --help                  Show this help message
 ";
 
+#[derive(Debug, Default)]
+enum Command {
+    #[default]
+    Update,
+    Verify,
+    Repair,
+    Rollback,
+}
+
 #[derive(Debug)]
 struct Args {
+    command: Command,
     game_path: Option<PathBuf>,
     archives_path: Option<PathBuf>,
+    work_dir: Option<PathBuf>,
+    game: Option<String>,
+    json_report: Option<PathBuf>,
+    dry_run: bool,
+    options: PatchOptions,
 }
 
 impl Args {
     fn parse() -> Self {
+        let mut command = Command::default();
         let mut game_path = Option::default();
         let mut archives_path = Option::default();
+        let mut work_dir = Option::default();
+        let mut game = Option::default();
+        let mut json_report = Option::default();
+        let mut dry_run = false;
+        let mut options = PatchOptions::default();
 
         let mut args = env::args().skip(1);
         while let Some(arg) = args.next() {
+            // Also accept `--flag=value`
+            let (arg, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with('-') => {
+                    (flag.to_string(), Some(value.to_string()))
+                }
+                _ => (arg, None),
+            };
+            let mut value = |name: &str| {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .unwrap_or_else(|| panic!("Missing value for {}", name))
+            };
+
             match arg.as_str() {
                 "-g" | "--game-path" => {
-                    game_path = Some(PathBuf::from(
-                        args.next().expect("Missing value for --game-path"),
-                    ));
+                    game_path = Some(PathBuf::from(value("--game-path")));
                 }
                 "-a" | "--archives-path" => {
-                    archives_path = Some(PathBuf::from(
-                        args.next().expect("Missing value for --archives-path"),
-                    ));
+                    archives_path = Some(PathBuf::from(value("--archives-path")));
+                }
+                "-w" | "--work-dir" => {
+                    work_dir = Some(PathBuf::from(value("--work-dir")));
+                }
+                "--game" => {
+                    game = Some(value("--game"));
                 }
+                "-j" | "--json" => {
+                    json_report = Some(PathBuf::from(value("--json")));
+                }
+                "--on-modified" => {
+                    options.on_modified = value("--on-modified")
+                        .parse()
+                        .expect("Invalid value for --on-modified");
+                }
+                "--dry-run" => dry_run = true,
+                "--atomic" => options.atomic = true,
+                "--keep-mtime" => options.keep_mtime = true,
+                "--low-disk" => options.low_disk = true,
+                "--backups" => {
+                    options.backups = value("--backups")
+                        .parse()
+                        .expect("Invalid value for --backups");
+                }
+                "update" => command = Command::Update,
+                "verify" => command = Command::Verify,
+                "repair" => command = Command::Repair,
+                "rollback" => command = Command::Rollback,
                 "-h" | "--help" => {
                     println!("{}", USAGE);
                     process::exit(0);
@@ -60,8 +147,14 @@ impl Args {
         }
 
         Self {
+            command,
             game_path,
             archives_path,
+            work_dir,
+            game,
+            json_report,
+            dry_run,
+            options,
         }
     }
 }
@@ -78,6 +171,7 @@ fn main() {
     let result: Result<()> = try {
         // Throw any error early if they occur
         SevenZip::instance().map_err(|e| anyhow!(e))?;
+        let cancel = CancelToken::install()?;
 
         // If args.game_path is None, default to env::current_dir()
         let game_path = args
@@ -87,7 +181,84 @@ fn main() {
         // If args.archives_path is None, default to game_path
         let archives_path = args.archives_path.as_deref().unwrap_or(game_path.as_path());
 
-        app::run(&game_path, archives_path)?;
+        // Nothing below may create files in a mistyped game path
+        if !game_path.is_dir() {
+            Err(anyhow!(
+                "'{}' is not a valid directory",
+                game_path.display()
+            ))?;
+        }
+
+        let (profile, selection) = GameProfile::select(&game_path, args.game.as_deref())?;
+        app::print_game(&profile, selection);
+
+        // Flags a command doesn't use would otherwise be ignored, a dry run of `repair` would change the game
+        if args.dry_run && !matches!(args.command, Command::Update) {
+            Err(anyhow!("--dry-run only works with update"))?;
+        }
+        if args.json_report.is_some() && !args.dry_run && !matches!(args.command, Command::Verify) {
+            Err(anyhow!("--json only works with verify or --dry-run"))?;
+        }
+
+        if args.options.atomic && args.options.low_disk {
+            // Low disk mode changes the game while a package is still being applied
+            Err(anyhow!("--low-disk can't be combined with --atomic"))?;
+        }
+        if args.options.backups > 0 && args.options.low_disk {
+            // Low disk mode drops every file it replaced as soon as it can, there'd be nothing to back up
+            Err(anyhow!("--low-disk can't be combined with --backups"))?;
+        }
+
+        if args.dry_run && matches!(args.command, Command::Update) {
+            // Only reads the game and the archives, so there's nothing to lock or recover
+            app::dry_run(
+                &game_path,
+                archives_path,
+                &profile,
+                selection,
+                &args.options,
+                args.json_report.as_deref(),
+            )?;
+        } else {
+            // If args.work_dir is None, default to game_path
+            if let Some(work_dir) = &args.work_dir {
+                fs::create_dir_all(work_dir).with_context(|| {
+                    format!("Failed to create work directory '{}'", work_dir.display())
+                })?;
+            }
+            let work_dir = args.work_dir.as_deref().unwrap_or(game_path.as_path());
+
+            let _lock = lock::GameLock::acquire(&game_path)?;
+            // A work directory shared between installs would have them clean up each other's files
+            let _work_lock = (work_dir != game_path)
+                .then(|| lock::GameLock::acquire(work_dir))
+                .transpose()?;
+            // Verify only reads the game, but recovering an interrupted update moves its files
+            if !matches!(args.command, Command::Verify) || transaction::has_journal(&game_path) {
+                lock::ensure_game_not_running(&game_path, &profile.executable)?;
+            }
+
+            app::recover(&game_path, work_dir)?;
+
+            match args.command {
+                Command::Update => app::run(
+                    &game_path,
+                    archives_path,
+                    work_dir,
+                    &profile,
+                    selection,
+                    &args.options,
+                    &cancel,
+                )?,
+                Command::Verify => {
+                    app::verify(&game_path, &profile, args.json_report.as_deref(), &cancel)?
+                }
+                Command::Repair => {
+                    app::repair(&game_path, archives_path, work_dir, &profile, &cancel)?
+                }
+                Command::Rollback => app::rollback(&game_path, &cancel)?,
+            }
+        }
     };
 
     if let Err(e) = result {
@@ -4,12 +4,16 @@ use std::{
     path::Path,
 };
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Result, bail};
 use indicatif::ProgressBar;
 
 use crate::{
-    patchers::Patcher,
+    cancel::CancelToken,
+    patchers::{self, PatchContext, Patcher},
+    safe_path,
+    transaction::Transaction,
     types::{CustomDiffMap, DiffEntry, HDiffMap},
+    verify::{self, ExpectedFile},
 };
 
 #[derive(Clone, Copy)]
@@ -18,6 +22,15 @@ enum HdiffFormat {
     Map,
 }
 
+impl HdiffFormat {
+    fn file_name(self) -> &'static str {
+        match self {
+            HdiffFormat::Files => "hdifffiles.txt",
+            HdiffFormat::Map => "hdiffmap.json",
+        }
+    }
+}
+
 pub struct Hdiff;
 
 impl Hdiff {
@@ -32,46 +45,103 @@ impl Hdiff {
     }
 
     fn load_diff_entries(patch_path: &Path, format: HdiffFormat) -> Result<Vec<DiffEntry>> {
+        let data = fs::read_to_string(patch_path.join(format.file_name()))?;
+        let diff_entries = Self::parse_diff_entries(&data, format)?;
+        patchers::check_paths(&diff_entries, format.file_name())?;
+
+        Ok(diff_entries)
+    }
+
+    fn parse_diff_entries(data: &str, format: HdiffFormat) -> Result<Vec<DiffEntry>> {
         match format {
-            HdiffFormat::Files => {
-                let path = patch_path.join("hdifffiles.txt");
-                let data = fs::read_to_string(&path)?;
-
-                data.lines()
-                    .filter(|l| !l.trim().is_empty())
-                    .map(|line| {
-                        serde_json::from_str::<CustomDiffMap>(line.trim())
-                            .with_context(|| format!("Failed to parse line: {}", line))
-                            .map(|entry| DiffEntry {
-                                source_file_name: entry.remote_name.clone(),
-                                patch_file_name: format!("{}.hdiff", entry.remote_name),
-                                target_file_name: entry.remote_name,
-                                ..Default::default()
-                            })
-                    })
-                    .collect()
-            }
+            HdiffFormat::Files => Ok(CustomDiffMap::parse_lines(data)?
+                .into_iter()
+                .map(|entry| DiffEntry {
+                    source_file_name: entry.remote_name.clone(),
+                    patch_file_name: format!("{}.hdiff", entry.remote_name),
+                    target_file_name: entry.remote_name,
+                    ..Default::default()
+                })
+                .collect()),
 
             HdiffFormat::Map => {
-                let path = patch_path.join("hdiffmap.json");
-                let data = fs::read_to_string(&path)?;
-                let map: HDiffMap = serde_json::from_str(&data)?;
+                let map: HDiffMap = serde_json::from_str(data)?;
                 Ok(map.diff_map)
             }
         }
     }
 
+    /// Total and largest size of the files patched from `metadata`, entries without a recorded size
+    /// are assumed to stay as large as they are in the game
+    pub fn output_size(game_path: &Path, metadata: &str, data: &str) -> Result<(u64, u64)> {
+        let format = match metadata {
+            "hdifffiles.txt" => HdiffFormat::Files,
+            _ => HdiffFormat::Map,
+        };
+
+        Ok(Self::parse_diff_entries(data, format)?
+            .iter()
+            .map(|entry| match entry.target_file_size {
+                0 => fs::metadata(game_path.join(&entry.target_file_name))
+                    .map_or(0, |metadata| metadata.len()),
+                size => size,
+            })
+            .fold((0, 0), |(total, largest), size| {
+                (total + size, largest.max(size))
+            }))
+    }
+
+    fn verify_patches(
+        patch_path: &Path,
+        diff_entries: &[DiffEntry],
+        cancel: &CancelToken,
+        progress: &ProgressBar,
+    ) -> Result<()> {
+        let patches: Vec<ExpectedFile> = diff_entries
+            .iter()
+            .map(|entry| ExpectedFile {
+                name: &entry.patch_file_name,
+                size: entry.patch_file_size,
+                md5: &entry.patch_file_md5,
+            })
+            .collect();
+
+        progress.set_message("Checking patches");
+        verify::ensure_files(
+            patch_path,
+            &patches,
+            None,
+            cancel,
+            progress,
+            "patch file(s) are missing or corrupt",
+        )
+    }
+
     fn cleanup(patch_path: &Path, diff_entries: &[DiffEntry]) {
         for entry in diff_entries {
             let _ = fs::remove_file(patch_path.join(&entry.patch_file_name));
         }
     }
 
-    fn apply_delete_list(game_path: &Path, patch_path: &Path) -> Result<()> {
+    /// Plans the deletions in `deletefiles.txt`, none of them may touch a file the patch set writes
+    fn plan_delete_list(
+        patch_path: &Path,
+        diff_entries: &[DiffEntry],
+        tx: &Transaction,
+    ) -> Result<()> {
+        for name in Self::read_delete_list(patch_path, diff_entries)? {
+            tx.delete(&name);
+        }
+
+        Ok(())
+    }
+
+    fn read_delete_list(patch_path: &Path, diff_entries: &[DiffEntry]) -> Result<Vec<String>> {
         let path = patch_path.join("deletefiles.txt");
+        let mut names = Vec::new();
 
         if !path.exists() {
-            return Ok(());
+            return Ok(names);
         }
 
         let file = File::open(&path)?;
@@ -84,33 +154,71 @@ impl Hdiff {
             if trimmed.is_empty() {
                 continue;
             }
+            safe_path::check(trimmed, "deletefiles.txt")?;
+
+            if diff_entries
+                .iter()
+                .any(|entry| entry.target_file_name == trimmed)
+            {
+                bail!(
+                    "deletefiles.txt deletes '{}' which the update patches",
+                    trimmed
+                );
+            }
 
-            let _ = fs::remove_file(game_path.join(trimmed));
+            names.push(trimmed.to_string());
         }
 
-        Ok(())
+        Ok(names)
     }
 }
 
 impl Patcher for Hdiff {
-    fn start(&self, game_path: &Path, patch_path: &Path, progress: &ProgressBar) -> Result<()> {
-        let format = Self::detect_format(patch_path)?;
-        let diff_entries = Self::load_diff_entries(patch_path, format)?;
-
-        match self.patch_files(game_path, patch_path, &diff_entries, progress) {
-            Ok(_) => {
-                Self::apply_delete_list(game_path, patch_path)?;
-                Self::cleanup(patch_path, &diff_entries);
-                Ok(())
-            }
-            Err(e) => {
-                Self::cleanup(patch_path, &diff_entries);
-                Err(e)
-            }
-        }
+    fn start(
+        &self,
+        game_path: &Path,
+        patch_path: &Path,
+        ctx: &PatchContext,
+        progress: &ProgressBar,
+    ) -> Result<()> {
+        let diff_entries = self.prepare(patch_path, ctx.cancel, progress)?;
+
+        let result = self
+            .patch_files(game_path, patch_path, &diff_entries, ctx, progress)
+            .and_then(|_| Self::plan_delete_list(patch_path, &diff_entries, ctx.tx));
+        Self::cleanup(patch_path, &diff_entries);
+
+        result
     }
 
     fn name(&self) -> &'static str {
         "hdiff"
     }
+
+    fn load_entries(&self, patch_path: &Path) -> Result<Vec<DiffEntry>> {
+        let format = Self::detect_format(patch_path)?;
+        Self::load_diff_entries(patch_path, format)
+    }
+
+    fn planned_deletions(
+        &self,
+        patch_path: &Path,
+        diff_entries: &[DiffEntry],
+    ) -> Result<Vec<String>> {
+        Self::read_delete_list(patch_path, diff_entries)
+    }
+
+    fn prepare(
+        &self,
+        patch_path: &Path,
+        cancel: &CancelToken,
+        progress: &ProgressBar,
+    ) -> Result<Vec<DiffEntry>> {
+        let format = Self::detect_format(patch_path)?;
+        let diff_entries = Self::load_diff_entries(patch_path, format)?;
+
+        Self::verify_patches(patch_path, &diff_entries, cancel, progress)?;
+
+        Ok(diff_entries)
+    }
 }
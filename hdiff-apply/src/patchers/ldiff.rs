@@ -1,32 +1,49 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
+use std::sync::Mutex;
 use std::{fs::File, path::PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use indicatif::ProgressBar;
 use prost::Message;
 use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
 
-use crate::patchers::Patcher;
-use crate::sophon_proto::{SophonPatchAssetChunk, SophonPatchAssetProperty, SophonPatchProto};
+use crate::app::{RESET, YELLOW};
+use crate::cancel::CancelToken;
+use crate::patchers::{PatchContext, Patcher};
+use crate::safe_path;
+use crate::sophon_proto::{
+    SophonPatchAssetChunk, SophonPatchAssetProperty, SophonPatchProto, SophonUnusedAssetFile,
+};
+use crate::transaction::Current;
 use crate::types::DiffEntry;
+use crate::verify::{self, ExpectedFile, FileState};
 
 pub struct Ldiff {
     manifest_path: PathBuf,
+    /// Chunk slices by patch file name, only sliced when they're needed in low disk mode
+    slices: Mutex<HashMap<String, SophonPatchAssetChunk>>,
 }
 
 impl Ldiff {
     pub fn new(manifest_path: PathBuf) -> Self {
-        Self { manifest_path }
+        Self {
+            manifest_path,
+            slices: Mutex::new(HashMap::new()),
+        }
     }
 
     fn load_manifest(manifest_path: &Path) -> Result<SophonPatchProto> {
         let manifest_file =
             File::open(manifest_path).context("Failed to open ldiff manifest file")?;
 
-        let mut decoder = zstd::Decoder::new(manifest_file)?;
+        Self::decode_manifest(manifest_file)
+    }
+
+    fn decode_manifest(manifest: impl Read) -> Result<SophonPatchProto> {
+        let mut decoder = zstd::Decoder::new(manifest)?;
         let mut manifest_decompressed = Vec::new();
         decoder
             .read_to_end(&mut manifest_decompressed)
@@ -47,6 +64,35 @@ impl Ldiff {
         })
     }
 
+    /// Names of the game files the manifest patches or adds
+    pub fn asset_names(manifest: &[u8]) -> Result<Vec<String>> {
+        let manifest = Self::decode_manifest(manifest)?;
+
+        Ok(manifest
+            .patch_assets
+            .into_iter()
+            .map(|asset_prop| asset_prop.asset_name)
+            .collect())
+    }
+
+    /// Sizes of the hdiff files the manifest slices out of its chunks, of the files it patches and
+    /// of the largest of those
+    pub fn output_size(manifest: &[u8]) -> Result<(u64, u64, u64)> {
+        let manifest = Self::decode_manifest(manifest)?;
+
+        Ok(Self::asset_pairs(&manifest).fold(
+            (0, 0, 0),
+            |(generated, patched, largest), (asset_prop, chunk)| {
+                let size = asset_prop.asset_size.max(0) as u64;
+                (
+                    generated + chunk.patch_length.max(0) as u64,
+                    patched + size,
+                    largest.max(size),
+                )
+            },
+        ))
+    }
+
     fn get_patch_file_name(asset_name: &str, original_path: &str) -> String {
         if original_path.is_empty() {
             format!("{}.hdiff", asset_name)
@@ -75,6 +121,91 @@ impl Ldiff {
             .collect()
     }
 
+    fn check_paths(manifest: &SophonPatchProto) -> Result<()> {
+        for (asset_prop, chunk) in Self::asset_pairs(manifest) {
+            safe_path::check(&asset_prop.asset_name, "manifest")?;
+            if !chunk.original_file_name.is_empty() {
+                safe_path::check(&chunk.original_file_name, "manifest")?;
+            }
+            safe_path::check(&chunk.patch_name, "manifest")?;
+        }
+
+        for asset in Self::unused_assets(manifest) {
+            safe_path::check(&asset.file_name, "manifest")?;
+        }
+
+        Ok(())
+    }
+
+    /// Sources that end up unused once their entries are patched, a source another entry
+    /// patches into stays since it's replaced instead
+    fn obsolete_sources<'a>(
+        diff_entries: &'a [DiffEntry],
+        skipped: &HashSet<&str>,
+    ) -> Vec<&'a str> {
+        let targets: HashSet<_> = diff_entries
+            .iter()
+            .map(|entry| entry.target_file_name.as_str())
+            .collect();
+
+        diff_entries
+            .iter()
+            .map(|entry| entry.source_file_name.as_str())
+            .filter(|source| {
+                !source.is_empty() && !targets.contains(source) && !skipped.contains(source)
+            })
+            .collect()
+    }
+
+    /// Checks every chunk file against its declared size and md5, and every slice against the chunk bounds
+    fn verify_chunks(
+        manifest: &SophonPatchProto,
+        patch_path: &Path,
+        cancel: &CancelToken,
+        progress: &ProgressBar,
+    ) -> Result<()> {
+        let mut seen = HashSet::new();
+        let mut chunks = Vec::new();
+
+        // The slices are written next to the manifest before any diff entry exists
+        Self::check_paths(manifest)?;
+
+        for (asset_prop, chunk) in Self::asset_pairs(manifest) {
+            if chunk.patch_offset < 0
+                || chunk.patch_length < 0
+                || (chunk.patch_size > 0
+                    && chunk.patch_offset + chunk.patch_length > chunk.patch_size)
+            {
+                bail!(
+                    "Slice for '{}' is out of bounds of chunk '{}' (offset {}, length {}, chunk size {})",
+                    asset_prop.asset_name,
+                    chunk.patch_name,
+                    chunk.patch_offset,
+                    chunk.patch_length,
+                    chunk.patch_size
+                );
+            }
+
+            if seen.insert(chunk.patch_name.as_str()) {
+                chunks.push(ExpectedFile {
+                    name: &chunk.patch_name,
+                    size: chunk.patch_size as u64,
+                    md5: &chunk.patch_md5,
+                });
+            }
+        }
+
+        progress.set_message("Checking patches");
+        verify::ensure_files(
+            &patch_path.join("ldiff"),
+            &chunks,
+            None,
+            cancel,
+            progress,
+            "ldiff chunk(s) are missing or corrupt",
+        )
+    }
+
     fn extract_hdiff_files(manifest: &SophonPatchProto, patch_path: &Path) -> Result<()> {
         Self::asset_pairs(manifest)
             .collect::<Vec<_>>()
@@ -82,90 +213,187 @@ impl Ldiff {
             .map(|(asset_prop, chunk)| {
                 let patch_file_name =
                     Self::get_patch_file_name(&asset_prop.asset_name, &chunk.original_file_name);
+                Self::extract_hdiff_file(patch_path, &patch_file_name, chunk)
+            })
+            .collect::<Result<Vec<_>>>()?;
 
-                let chunk_path = patch_path.join("ldiff").join(&chunk.patch_name);
-                let output_path = patch_path.join(&patch_file_name);
+        Ok(())
+    }
 
-                if let Some(parent) = output_path.parent() {
-                    fs::create_dir_all(parent)?;
-                }
+    fn extract_hdiff_file(
+        patch_path: &Path,
+        patch_file_name: &str,
+        chunk: &SophonPatchAssetChunk,
+    ) -> Result<()> {
+        let chunk_path = patch_path.join("ldiff").join(&chunk.patch_name);
+        let output_path = patch_path.join(patch_file_name);
 
-                let mut chunk_file = File::open(&chunk_path)
-                    .with_context(|| format!("Failed to open chunk: {}", chunk.patch_name))?;
+        if let Some(parent) = output_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
 
-                chunk_file.seek(SeekFrom::Start(chunk.patch_offset as u64))?;
+        let mut chunk_file = File::open(&chunk_path)
+            .with_context(|| format!("Failed to open chunk: {}", chunk.patch_name))?;
 
-                let mut hdiff_bytes = vec![0u8; chunk.patch_length as usize];
-                chunk_file.read_exact(&mut hdiff_bytes)?;
+        chunk_file.seek(SeekFrom::Start(chunk.patch_offset as u64))?;
 
-                fs::write(&output_path, hdiff_bytes)
-                    .with_context(|| format!("Failed to write: {}", patch_file_name))?;
+        let mut hdiff_bytes = vec![0u8; chunk.patch_length as usize];
+        chunk_file.read_exact(&mut hdiff_bytes)?;
 
-                Ok(())
-            })
-            .collect::<Result<Vec<_>>>()?;
+        fs::write(&output_path, hdiff_bytes)
+            .with_context(|| format!("Failed to write: {}", patch_file_name))?;
 
         Ok(())
     }
 
+    fn read_manifest(manifest_path: &Path, progress: &ProgressBar) -> Result<SophonPatchProto> {
+        progress.unset_length();
+        progress.set_message("Reading manifest");
+        Self::load_manifest(manifest_path)
+    }
+
+    /// Verifies the chunks and slices them into standalone hdiff files next to the manifest
+    fn prepare_manifest(
+        manifest: &SophonPatchProto,
+        patch_path: &Path,
+        cancel: &CancelToken,
+        progress: &ProgressBar,
+    ) -> Result<Vec<DiffEntry>> {
+        Self::verify_chunks(manifest, patch_path, cancel, progress)?;
+
+        progress.unset_length();
+        progress.set_message("Extracting files");
+        Self::extract_hdiff_files(manifest, patch_path)
+            .context("Failed to extract hdiff files from ldiff")?;
+
+        Self::create_diff_entries(manifest).context("Failed to create diff entries")
+    }
+
     fn cleanup_generated_hdiff(patch_path: &Path, diff_entries: &[DiffEntry]) {
         diff_entries.par_iter().for_each(|entry| {
             let _ = fs::remove_file(patch_path.join(&entry.patch_file_name));
         });
     }
 
-    fn cleanup_old_files(
+    fn unused_assets(manifest: &SophonPatchProto) -> Vec<&SophonUnusedAssetFile> {
+        manifest
+            .unused_assets
+            .iter()
+            .flat_map(|unused| &unused.asset_infos)
+            .flat_map(|info| &info.assets)
+            .collect()
+    }
+
+    /// Plans deleting what the update made obsolete and returns the files that were kept
+    fn plan_cleanup(
         game_path: &Path,
         diff_entries: &[DiffEntry],
+        skipped: &[&DiffEntry],
         manifest: &SophonPatchProto,
-    ) -> Result<()> {
-        diff_entries.par_iter().for_each(|entry| {
-            if !entry.source_file_name.is_empty() {
-                let source_file = game_path.join(&entry.source_file_name);
-                let target_file = game_path.join(&entry.target_file_name);
-                if source_file != target_file {
-                    let _ = fs::remove_file(source_file);
+        ctx: &PatchContext,
+    ) -> Result<Vec<(String, String)>> {
+        let skipped: HashSet<_> = skipped
+            .iter()
+            .map(|entry| entry.source_file_name.as_str())
+            .collect();
+
+        for source in Self::obsolete_sources(diff_entries, &skipped) {
+            ctx.tx.delete(source);
+        }
+
+        let targets: HashSet<_> = diff_entries
+            .iter()
+            .map(|entry| entry.target_file_name.as_str())
+            .collect();
+
+        // Only assets the manifest lists as unused are deleted, and only if they are exactly what it recorded
+        let unused = Self::unused_assets(manifest);
+        let mut kept = unused
+            .par_iter()
+            .map(|asset| -> Result<Option<(String, String)>> {
+                ctx.cancel.check()?;
+                if targets.contains(asset.file_name.as_str()) {
+                    bail!(
+                        "'{}' is listed as unused but the update patches it",
+                        asset.file_name
+                    );
                 }
-            }
-        });
+                if asset.file_md5.is_empty() {
+                    return Ok(Some((asset.file_name.clone(), "no recorded md5".into())));
+                }
+
+                let file = ExpectedFile {
+                    name: &asset.file_name,
+                    size: asset.file_size as u64,
+                    md5: &asset.file_md5,
+                };
+                // `--on-modified` is about patching, a modified asset is kept under every policy
+                match ctx.check_file(game_path, &file)? {
+                    FileState::Missing => Ok(None),
+                    FileState::Ok => {
+                        ctx.tx.delete(&asset.file_name);
+                        Ok(None)
+                    }
+                    state => Ok(Some((asset.file_name.clone(), state.to_string()))),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
 
-        let asset_set: HashSet<_> = manifest
+        // Anything else the update doesn't know about may be user-added, so it's only reported
+        let known: HashSet<_> = manifest
             .patch_assets
             .iter()
             .map(|asset_prop| PathBuf::from(&asset_prop.asset_name))
+            .chain(unused.iter().map(|asset| PathBuf::from(&asset.file_name)))
+            .chain(
+                diff_entries
+                    .iter()
+                    .map(|entry| PathBuf::from(&entry.source_file_name)),
+            )
             .collect();
 
-        let data_path = game_path.join("StarRail_Data");
-        if !data_path.exists() {
-            return Ok(());
-        }
-
         let mut all_files = Vec::new();
-        Self::collect_files_skip_dir(&data_path, "Persistent", &mut all_files)?;
+        for data_dir in &ctx.profile.data_dirs {
+            let data_path = game_path.join(data_dir);
+            if data_path.is_dir() {
+                let protected: Vec<PathBuf> = ctx
+                    .profile
+                    .protected_dirs
+                    .iter()
+                    .map(|dir| data_path.join(dir))
+                    .collect();
+                Self::collect_files_skip_dirs(&data_path, &protected, &mut all_files)?;
+            }
+        }
 
-        let files_to_delete: Vec<_> = all_files
-            .into_par_iter()
-            .filter(|path| {
-                path.strip_prefix(game_path)
-                    .map(|rel| !asset_set.contains(rel))
-                    .unwrap_or(false)
+        kept.extend(all_files.into_iter().filter_map(|path| {
+            let rel = path.strip_prefix(game_path).ok()?;
+            // Files an earlier package of the chain deletes aren't left over
+            let deleted = matches!(ctx.tx.current(&rel.to_string_lossy()), Current::Deleted);
+            (!known.contains(rel) && !deleted).then(|| {
+                (
+                    rel.to_string_lossy().into_owned(),
+                    "not part of this update".into(),
+                )
             })
-            .collect();
-
-        files_to_delete.into_iter().try_for_each(fs::remove_file)?;
+        }));
 
-        Ok(())
+        kept.sort();
+        Ok(kept)
     }
 
-    fn collect_files_skip_dir(dir: &Path, skip_name: &str, out: &mut Vec<PathBuf>) -> Result<()> {
+    fn collect_files_skip_dirs(dir: &Path, skip: &[PathBuf], out: &mut Vec<PathBuf>) -> Result<()> {
         for entry in fs::read_dir(dir)? {
             let entry = entry?;
             let path = entry.path();
             if entry.file_type()?.is_dir() {
-                if entry.file_name() == skip_name {
+                if skip.contains(&path) {
                     continue;
                 }
-                Self::collect_files_skip_dir(&path, skip_name, out)?;
+                Self::collect_files_skip_dirs(&path, skip, out)?;
             } else {
                 out.push(path);
             }
@@ -176,31 +404,96 @@ impl Ldiff {
 }
 
 impl Patcher for Ldiff {
-    fn start(&self, game_path: &Path, patch_path: &Path, progress: &ProgressBar) -> Result<()> {
-        progress.unset_length();
-        progress.set_message("Reading manifest");
-        let manifest = Self::load_manifest(&self.manifest_path)?;
-
-        progress.set_message("Extracting files");
-        Self::extract_hdiff_files(&manifest, patch_path)
-            .context("Failed to extract hdiff files from ldiff")?;
-
-        let diff_entries =
-            Self::create_diff_entries(&manifest).context("Failed to create diff entries")?;
-
-        match self.patch_files(game_path, patch_path, &diff_entries, progress) {
-            Ok(_) => {
-                Self::cleanup_generated_hdiff(patch_path, &diff_entries);
-                Self::cleanup_old_files(game_path, &diff_entries, &manifest)
-            }
-            Err(e) => {
-                Self::cleanup_generated_hdiff(patch_path, &diff_entries);
-                Err(e)
+    fn start(
+        &self,
+        game_path: &Path,
+        patch_path: &Path,
+        ctx: &PatchContext,
+        progress: &ProgressBar,
+    ) -> Result<()> {
+        let manifest = Self::read_manifest(&self.manifest_path, progress)?;
+        let diff_entries = if ctx.options.low_disk {
+            // Slicing every hdiff up front would take as much space again as the chunks
+            Self::verify_chunks(&manifest, patch_path, ctx.cancel, progress)?;
+            *self.slices.lock().unwrap() = Self::asset_pairs(&manifest)
+                .map(|(asset_prop, chunk)| {
+                    let patch_file_name = Self::get_patch_file_name(
+                        &asset_prop.asset_name,
+                        &chunk.original_file_name,
+                    );
+                    (patch_file_name, chunk.clone())
+                })
+                .collect();
+            Self::create_diff_entries(&manifest).context("Failed to create diff entries")?
+        } else {
+            Self::prepare_manifest(&manifest, patch_path, ctx.cancel, progress)?
+        };
+
+        let result = self
+            .patch_files(game_path, patch_path, &diff_entries, ctx, progress)
+            .and_then(|skipped| {
+                Self::plan_cleanup(game_path, &diff_entries, &skipped, &manifest, ctx)
+            });
+        Self::cleanup_generated_hdiff(patch_path, &diff_entries);
+
+        let kept = result?;
+        if !kept.is_empty() {
+            progress.println(format!(
+                "  {YELLOW}Kept {} file(s) that weren't safe to delete{RESET}:",
+                kept.len()
+            ));
+            for (name, reason) in kept {
+                progress.println(format!("    {name} ({reason})"));
             }
         }
+
+        Ok(())
     }
 
     fn name(&self) -> &'static str {
         "ldiff"
     }
+
+    fn load_entries(&self, _patch_path: &Path) -> Result<Vec<DiffEntry>> {
+        let manifest = Self::load_manifest(&self.manifest_path)?;
+        Self::check_paths(&manifest)?;
+        Self::create_diff_entries(&manifest)
+    }
+
+    /// Unused assets are listed whether or not they'd pass the hash check
+    fn planned_deletions(
+        &self,
+        _patch_path: &Path,
+        diff_entries: &[DiffEntry],
+    ) -> Result<Vec<String>> {
+        let manifest = Self::load_manifest(&self.manifest_path)?;
+
+        Ok(Self::obsolete_sources(diff_entries, &HashSet::new())
+            .into_iter()
+            .map(str::to_string)
+            .chain(
+                Self::unused_assets(&manifest)
+                    .into_iter()
+                    .map(|asset| asset.file_name.clone()),
+            )
+            .collect())
+    }
+
+    fn prepare(
+        &self,
+        patch_path: &Path,
+        cancel: &CancelToken,
+        progress: &ProgressBar,
+    ) -> Result<Vec<DiffEntry>> {
+        let manifest = Self::read_manifest(&self.manifest_path, progress)?;
+        Self::prepare_manifest(&manifest, patch_path, cancel, progress)
+    }
+
+    fn materialize_patch(&self, patch_path: &Path, entry: &DiffEntry) -> Result<()> {
+        let slices = self.slices.lock().unwrap();
+        match slices.get(&entry.patch_file_name) {
+            Some(chunk) => Self::extract_hdiff_file(patch_path, &entry.patch_file_name, chunk),
+            None => Ok(()),
+        }
+    }
 }
@@ -1,145 +1,626 @@
 use std::{
+    collections::HashMap,
     fs::{self, File},
     path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, UNIX_EPOCH},
 };
 
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
 use anyhow::{Context, Result, bail};
 use indicatif::ProgressBar;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 
 use crate::{
-    app::HaTemp,
+    app::{RESET, YELLOW},
+    cancel::CancelToken,
+    game_profile::GameProfile,
+    hash_cache::HashCache,
     patchers::{hdiff::Hdiff, ldiff::Ldiff},
+    safe_path,
+    transaction::{Current, Transaction},
     types::DiffEntry,
+    verify::{self, ExpectedFile, FileState},
 };
 
 mod hdiff;
 mod ldiff;
 
 pub trait Patcher {
-    fn start(&self, game_path: &Path, patch_path: &Path, progress: &ProgressBar) -> Result<()>;
+    fn start(
+        &self,
+        game_path: &Path,
+        patch_path: &Path,
+        ctx: &PatchContext,
+        progress: &ProgressBar,
+    ) -> Result<()>;
     fn name(&self) -> &'static str;
 
-    fn patch_files(
+    /// Loads the diff entries and makes sure every patch file they reference is on disk and intact
+    fn prepare(
         &self,
-        game_path: &Path,
         patch_path: &Path,
-        diff_entries: &[DiffEntry],
+        cancel: &CancelToken,
         progress: &ProgressBar,
-    ) -> Result<()> {
-        let staging_dir = HaTemp::new(game_path.join(".ha-staging"))?;
+    ) -> Result<Vec<DiffEntry>>;
 
-        progress.set_message("Patching files");
+    /// Loads the diff entries from the patch metadata alone, the patch files don't have to be there
+    fn load_entries(&self, patch_path: &Path) -> Result<Vec<DiffEntry>>;
+
+    /// Files the package deletes besides the ones it patches
+    fn planned_deletions(
+        &self,
+        patch_path: &Path,
+        diff_entries: &[DiffEntry],
+    ) -> Result<Vec<String>>;
+
+    /// Writes the entry's patch file when the patcher only produces it on demand, see [`PatchOptions::low_disk`]
+    fn materialize_patch(&self, _patch_path: &Path, _entry: &DiffEntry) -> Result<()> {
+        Ok(())
+    }
+
+    /// Works out what is left to do for each entry so an interrupted update can be applied again.
+    /// Sources that are neither the original nor the updated file are handled by `on_modified`.
+    /// Returns the entries to patch, the ones to patch over local changes and the ones that were skipped
+    fn pending_entries<'a>(
+        &self,
+        game_path: &Path,
+        diff_entries: &'a [DiffEntry],
+        ctx: &PatchContext,
+        progress: &ProgressBar,
+    ) -> Result<(Vec<&'a DiffEntry>, Vec<&'a DiffEntry>, Vec<&'a DiffEntry>)> {
+        progress.set_message("Verifying files");
         progress.set_length(diff_entries.len() as _);
         progress.set_position(0);
 
-        // A hack for ldiffs since i wanna be sure "normal" diffs patch correctly before creating dummy files in the game folder
-        let (empty_source, normal): (Vec<&DiffEntry>, Vec<&DiffEntry>) = diff_entries
-            .iter()
-            .partition(|entry| entry.source_file_name.is_empty());
+        let states = diff_entries
+            .par_iter()
+            .map(|entry| -> Result<(&DiffEntry, EntryState)> {
+                ctx.cancel.check()?;
+                let state = EntryState::of(game_path, entry, ctx)?;
+                progress.inc(1);
+                Ok((entry, state))
+            })
+            .collect::<Result<Vec<_>>>()?;
 
-        normal.par_iter().try_for_each(|entry| -> Result<()> {
-            let source_file = game_path.join(&entry.source_file_name);
-            if !source_file.exists() {
-                anyhow::bail!("Missing source file: {}", source_file.display());
+        let mut pending = Vec::new();
+        let mut conflicts = Vec::new();
+        for (entry, state) in states {
+            match state {
+                EntryState::Pending => pending.push(entry),
+                EntryState::Applied => {}
+                EntryState::Conflict(state) => conflicts.push((entry, state)),
             }
+        }
 
-            let patch_file = patch_path.join(&entry.patch_file_name);
-            if !patch_file.exists() {
-                anyhow::bail!("Missing patch file: {}", patch_file.display());
+        let mut report = Vec::new();
+        let mut overwritten = Vec::new();
+        let mut skipped = Vec::new();
+        let mut any_missing = false;
+        for (entry, state) in conflicts {
+            any_missing |= matches!(state, FileState::Missing);
+            match ctx.options.on_modified {
+                OnModified::Abort => {}
+                OnModified::Skip => skipped.push(entry),
+                // The output is still verified, an entry the patch can't cope with is skipped after all
+                OnModified::Overwrite => overwritten.push(entry),
             }
+            report.push((entry.source_file_name.clone(), state));
+        }
 
-            let staged = staging_dir.join(&entry.target_file_name);
-            if let Some(parent) = staged.parent() {
-                fs::create_dir_all(parent)?;
-            }
+        if report.is_empty() {
+            return Ok((pending, overwritten, skipped));
+        }
 
-            hdiffpatch_rs::patch_hdiff(&source_file, &patch_file, &staged).map_err(|e| {
-                anyhow::anyhow!(e.to_string())
-                    .context(format!("Failed to patch '{}'", entry.target_file_name))
-            })?;
+        report.sort_by(|a, b| a.0.cmp(&b.0));
+        report.dedup_by(|a, b| a.0 == b.0);
 
-            progress.inc(1);
-            Ok(())
-        })?;
+        match ctx.options.on_modified {
+            OnModified::Skip => progress.println(format!(
+                "  {YELLOW}{}{RESET}",
+                verify::mismatch_report("locally modified file(s) were skipped", &report)
+            )),
+            // A missing source can't be patched no matter the policy
+            OnModified::Overwrite if !any_missing => progress.println(format!(
+                "  {YELLOW}{}{RESET}",
+                verify::mismatch_report(
+                    "locally modified file(s) will be overwritten where the patch still applies",
+                    &report
+                )
+            )),
+            _ => bail!(
+                "{}",
+                verify::mismatch_report(
+                    "source file(s) don't match the version this package was built for",
+                    &report
+                )
+            ),
+        }
 
-        empty_source
-            .par_iter()
-            .try_for_each(|entry| -> Result<()> {
-                let source_file = game_path.join(&entry.target_file_name);
-                let parent = source_file.parent().ok_or_else(|| {
-                    anyhow::anyhow!(
-                        "entry.target_file_name has no parent: {}",
-                        source_file.display()
-                    )
-                })?;
-                fs::create_dir_all(parent)?;
-                File::create(&source_file).with_context(|| {
-                    format!(
-                        "Failed to create dummy source file: {}",
-                        source_file.display()
-                    )
-                })?;
-
-                let patch_file = patch_path.join(&entry.patch_file_name);
-                if !patch_file.exists() {
-                    anyhow::bail!("Missing patch file: {}", patch_file.display());
-                }
+        Ok((pending, overwritten, skipped))
+    }
 
-                let staged = staging_dir.join(&entry.target_file_name);
-                if let Some(parent) = staged.parent() {
-                    fs::create_dir_all(parent)?;
-                }
+    /// Hashes every staged output so nothing reaches the game folder unless all of it is correct
+    fn verify_staged(
+        &self,
+        staging_dir: &Path,
+        diff_entries: &[&DiffEntry],
+        cancel: &CancelToken,
+        progress: &ProgressBar,
+    ) -> Result<()> {
+        let targets: Vec<ExpectedFile> = diff_entries
+            .iter()
+            .map(|entry| ExpectedFile {
+                name: &entry.target_file_name,
+                size: entry.target_file_size,
+                md5: &entry.target_file_md5,
+            })
+            .collect();
 
-                hdiffpatch_rs::patch_hdiff(&source_file, &patch_file, &staged).map_err(|e| {
-                    anyhow::anyhow!(e.to_string())
-                        .context(format!("Failed to patch '{}'", entry.target_file_name))
-                })?;
+        progress.set_message("Verifying output");
+        verify::ensure_files(
+            staging_dir,
+            &targets,
+            None,
+            cancel,
+            progress,
+            "patched file(s) failed verification",
+        )
+    }
 
+    /// Patches every pending entry into the staging directory and plans moving it into the game.
+    /// Returns the entries skipped because of local changes
+    fn patch_files<'a>(
+        &self,
+        game_path: &Path,
+        patch_path: &Path,
+        diff_entries: &'a [DiffEntry],
+        ctx: &PatchContext,
+        progress: &ProgressBar,
+    ) -> Result<Vec<&'a DiffEntry>> {
+        let (diff_entries, overwritten, mut skipped) =
+            self.pending_entries(game_path, diff_entries, ctx, progress)?;
+        if diff_entries.is_empty() && overwritten.is_empty() {
+            return Ok(skipped);
+        }
+
+        if ctx.options.low_disk {
+            let kept = self.patch_in_place(
+                game_path,
+                patch_path,
+                &diff_entries,
+                &overwritten,
+                ctx,
+                progress,
+            )?;
+            report_kept(&kept, progress);
+            skipped.extend(kept);
+            return Ok(skipped);
+        }
+
+        let staging_dir = ctx.tx.staging_dir();
+
+        progress.set_message("Patching files");
+        progress.set_length(diff_entries.len() as _);
+        progress.set_position(0);
+
+        diff_entries
+            .par_iter()
+            .try_for_each(|entry| -> Result<()> {
+                ctx.cancel.check()?;
+                stage_entry(game_path, patch_path, entry, ctx)?;
                 progress.inc(1);
                 Ok(())
             })?;
 
-        // Commit patched files into the game
-        progress.set_message("Merging files");
+        // To be 100% sure everything went smoothly
+        self.verify_staged(staging_dir, &diff_entries, ctx.cancel, progress)?;
+
+        progress.set_message("Patching modified files");
+        progress.set_length(overwritten.len() as _);
         progress.set_position(0);
-        progress.set_length(diff_entries.len() as _);
 
-        // To be 100% sure everything went smoothly
-        for entry in diff_entries.as_ref() {
-            let staged_file = staging_dir.join(&entry.target_file_name);
-            if !staged_file.exists() {
-                bail!("Staged file missing: {}", entry.target_file_name);
+        let results = overwritten
+            .par_iter()
+            .map(|&entry| -> Result<(&DiffEntry, bool)> {
+                ctx.cancel.check()?;
+                let patched = stage_over_changes(game_path, patch_path, entry, ctx)?;
+                progress.inc(1);
+                Ok((entry, patched))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut patched = diff_entries;
+        let mut kept = Vec::new();
+        for (entry, ok) in results {
+            if ok {
+                patched.push(entry);
+            } else {
+                kept.push(entry);
             }
         }
+        report_kept(&kept, progress);
+        skipped.extend(kept);
 
-        diff_entries
-            .par_iter()
-            .try_for_each(|entry| -> Result<()> {
-                let staged_file = staging_dir.join(&entry.target_file_name);
-                let target_file = game_path.join(&entry.target_file_name);
+        for entry in &patched {
+            ctx.tx.replace(
+                staging_dir.join(&entry.target_file_name),
+                &entry.target_file_name,
+                &entry.target_file_md5,
+            );
+        }
+
+        Ok(skipped)
+    }
+
+    /// Patches one entry at a time and moves it into the game straight away, so only the file being
+    /// patched and its patch take space on top of the game. A replaced file is dropped as soon as no
+    /// later entry patches from it, from then on its replacement stays even if the package fails.
+    /// `overwritten` are patched over local changes, returns the ones the patch couldn't be applied to
+    fn patch_in_place<'a>(
+        &self,
+        game_path: &Path,
+        patch_path: &Path,
+        diff_entries: &[&'a DiffEntry],
+        overwritten: &[&'a DiffEntry],
+        ctx: &PatchContext,
+        progress: &ProgressBar,
+    ) -> Result<Vec<&'a DiffEntry>> {
+        let staging_dir = ctx.tx.staging_dir();
+        let entries = diff_entries
+            .iter()
+            .map(|&entry| (entry, false))
+            .chain(overwritten.iter().map(|&entry| (entry, true)));
+
+        // How many of the entries left to patch read each file
+        let mut readers: HashMap<&str, usize> = HashMap::new();
+        for (entry, _) in entries.clone() {
+            *readers.entry(&entry.source_file_name).or_default() += 1;
+        }
+
+        progress.set_message("Patching files in place");
+        progress.set_length((diff_entries.len() + overwritten.len()) as _);
+        progress.set_position(0);
+
+        let mut kept = Vec::new();
+        for (entry, modified) in entries {
+            ctx.cancel.check()?;
+
+            self.materialize_patch(patch_path, entry)?;
+            let staged = staging_dir.join(&entry.target_file_name);
+            let patched = if modified {
+                stage_over_changes(game_path, patch_path, entry, ctx)?
+            } else {
+                stage_entry(game_path, patch_path, entry, ctx)?;
+                let state =
+                    verify::check_file(&staged, entry.target_file_size, &entry.target_file_md5)?;
+                if !state.is_ok() {
+                    bail!(
+                        "Patched file '{}' failed verification: {}",
+                        entry.target_file_name,
+                        state
+                    );
+                }
+                true
+            };
+            let _ = fs::remove_file(patch_path.join(&entry.patch_file_name));
+
+            if patched {
+                ctx.tx
+                    .apply_now(staged, &entry.target_file_name, &entry.target_file_md5)?;
+            } else {
+                kept.push(entry);
+            }
 
-                if let Some(parent) = target_file.parent() {
-                    fs::create_dir_all(parent).with_context(|| {
-                        format!("Failed to create target directory: {}", parent.display())
-                    })?;
+            if let Some(count) = readers.get_mut(entry.source_file_name.as_str()) {
+                *count -= 1;
+            }
+            for name in [&entry.source_file_name, &entry.target_file_name] {
+                if readers.get(name.as_str()).is_none_or(|&count| count == 0) {
+                    ctx.tx.release_original(name)?;
                 }
+            }
+            progress.inc(1);
+        }
 
-                fs::rename(&staged_file, &target_file)
-                    .or_else(|_| fs::copy(&staged_file, &target_file).map(|_| ()))
-                    .with_context(|| {
-                        format!("Failed to move into place: {}", entry.target_file_name)
-                    })?;
+        Ok(kept)
+    }
+}
 
-                progress.inc(1);
+/// Patches an entry whose source has local changes the patch may not cope with. Returns whether
+/// the staged output is the updated file, one that isn't is removed again
+fn stage_over_changes(
+    game_path: &Path,
+    patch_path: &Path,
+    entry: &DiffEntry,
+    ctx: &PatchContext,
+) -> Result<bool> {
+    let staged = ctx.tx.staging_dir().join(&entry.target_file_name);
+    let state = stage_entry(game_path, patch_path, entry, ctx)
+        .and_then(|_| verify::check_file(&staged, entry.target_file_size, &entry.target_file_md5));
 
-                Ok(())
-            })?;
+    match state {
+        Ok(state) if state.is_ok() => Ok(true),
+        _ => {
+            let _ = fs::remove_file(&staged);
+            Ok(false)
+        }
+    }
+}
 
-        Ok(())
+/// Lists the locally modified files that were left alone because their patch didn't apply
+fn report_kept(kept: &[&DiffEntry], progress: &ProgressBar) {
+    if kept.is_empty() {
+        return;
+    }
+
+    progress.println(format!(
+        "  {YELLOW}Kept {} locally modified file(s) the patch couldn't be applied to{RESET}:",
+        kept.len()
+    ));
+    for entry in kept {
+        progress.println(format!("    {}", entry.source_file_name));
+    }
+}
+
+/// Patches an entry into the staging directory and gives the result its metadata
+fn stage_entry(
+    game_path: &Path,
+    patch_path: &Path,
+    entry: &DiffEntry,
+    ctx: &PatchContext,
+) -> Result<()> {
+    let staging_dir = ctx.tx.staging_dir();
+
+    // Ldiffs can create new files from nothing, those get an empty source in staging
+    // so the game folder stays untouched until the commit
+    let source_file = if entry.source_file_name.is_empty() {
+        let empty = staging_dir.join(format!("{}.empty", entry.target_file_name));
+        if let Some(parent) = empty.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        File::create(&empty)
+            .with_context(|| format!("Failed to create empty source file: {}", empty.display()))?;
+        empty
+    } else {
+        ctx.source_path(game_path, &entry.source_file_name)
+            .filter(|path| path.exists())
+            .with_context(|| format!("Missing source file: {}", entry.source_file_name))?
+    };
+
+    stage_patch(&source_file, patch_path, staging_dir, entry)?;
+
+    if entry.source_file_name.is_empty() {
+        let _ = fs::remove_file(&source_file);
+        copy_metadata(None, staging_dir, entry, ctx.options.keep_mtime)
+    } else {
+        copy_metadata(
+            Some(&source_file),
+            staging_dir,
+            entry,
+            ctx.options.keep_mtime,
+        )
     }
 }
 
+/// What to do with game files that differ from the pre-update hashes in the patch metadata
+#[derive(Debug, Clone, Copy, Default)]
+pub enum OnModified {
+    /// Refuse the whole package
+    #[default]
+    Abort,
+    /// Leave the modified files alone and patch everything else
+    Skip,
+    /// Patch them anyway, a file whose output doesn't match the updated hash is skipped after all
+    Overwrite,
+}
+
+impl FromStr for OnModified {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "abort" => Ok(Self::Abort),
+            "skip" => Ok(Self::Skip),
+            "overwrite" => Ok(Self::Overwrite),
+            _ => bail!("Unknown policy '{}', expected abort, skip or overwrite", s),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PatchOptions {
+    pub on_modified: OnModified,
+    /// How many packages can be rolled back, 0 disables backups
+    pub backups: usize,
+    /// Commit all selected packages at once instead of one after another
+    pub atomic: bool,
+    /// Give patched files the modification time of the file they were patched from
+    pub keep_mtime: bool,
+    /// Patch and move files into the game one at a time instead of staging the whole package first
+    pub low_disk: bool,
+}
+
+/// State shared by every patcher for one package
+pub struct PatchContext<'a> {
+    pub cache: &'a HashCache,
+    pub options: &'a PatchOptions,
+    pub tx: &'a Transaction,
+    pub cancel: &'a CancelToken,
+    pub profile: &'a GameProfile,
+}
+
+impl PatchContext<'_> {
+    /// Checks a game file as earlier packages of the chain left it, or as it is on disk when nothing touched it
+    pub fn check_file(&self, game_path: &Path, file: &ExpectedFile) -> Result<FileState> {
+        match self.tx.current(file.name) {
+            Current::Game => verify::check_game_file(game_path, file, self.cache),
+            Current::Deleted => Ok(FileState::Missing),
+            // Staged files were verified when they were planned, their hash doesn't have to be computed again
+            Current::Staged { path, md5 } if !md5.is_empty() && !file.md5.is_empty() => {
+                let actual = fs::metadata(&path)?.len();
+                if actual != file.size {
+                    Ok(FileState::SizeMismatch {
+                        expected: file.size,
+                        actual,
+                    })
+                } else if !md5.eq_ignore_ascii_case(file.md5) {
+                    Ok(FileState::HashMismatch {
+                        expected: file.md5.to_string(),
+                        actual: md5,
+                    })
+                } else {
+                    Ok(FileState::Ok)
+                }
+            }
+            Current::Staged { path, .. } => verify::check_file(&path, file.size, file.md5),
+        }
+    }
+
+    /// The path `name` can be read from, if earlier packages of the chain left it in place
+    pub fn source_path(&self, game_path: &Path, name: &str) -> Option<PathBuf> {
+        // A file already replaced in place is still read in its old version
+        if let Some(original) = self.tx.original_of(name) {
+            return Some(original);
+        }
+
+        match self.tx.current(name) {
+            Current::Game => Some(game_path.join(name)),
+            Current::Staged { path, .. } => Some(path),
+            Current::Deleted => None,
+        }
+    }
+}
+
+enum EntryState {
+    /// The source is intact and the entry still has to be patched
+    Pending,
+    /// The target already matches, e.g. from an earlier interrupted run
+    Applied,
+    Conflict(FileState),
+}
+
+impl EntryState {
+    fn of(game_path: &Path, entry: &DiffEntry, ctx: &PatchContext) -> Result<Self> {
+        // Without a target hash there's no telling whether the entry was applied already
+        if !entry.target_file_md5.is_empty() {
+            let target = ExpectedFile {
+                name: &entry.target_file_name,
+                size: entry.target_file_size,
+                md5: &entry.target_file_md5,
+            };
+            if ctx.check_file(game_path, &target)?.is_ok() {
+                return Ok(Self::Applied);
+            }
+        }
+
+        if entry.source_file_name.is_empty() {
+            return Ok(Self::Pending);
+        }
+
+        let source = ExpectedFile {
+            name: &entry.source_file_name,
+            size: entry.source_file_size,
+            md5: &entry.source_file_md5,
+        };
+        match ctx.check_file(game_path, &source)? {
+            FileState::Ok => Ok(Self::Pending),
+            state => Ok(Self::Conflict(state)),
+        }
+    }
+}
+
+/// Gives the patched file the source's permissions and, with `keep_mtime`, its modification time.
+/// A mode or mtime recorded in the patch metadata takes precedence over the source
+fn copy_metadata(
+    source_file: Option<&Path>,
+    staging_dir: &Path,
+    entry: &DiffEntry,
+    keep_mtime: bool,
+) -> Result<()> {
+    let staged = staging_dir.join(&entry.target_file_name);
+    let source = source_file.map(fs::metadata).transpose()?;
+
+    let mtime = match entry.target_file_mtime {
+        Some(secs) => Some(UNIX_EPOCH + Duration::from_secs(secs)),
+        None if keep_mtime => source.as_ref().map(|m| m.modified()).transpose()?,
+        None => None,
+    };
+    if let Some(mtime) = mtime {
+        File::options()
+            .write(true)
+            .open(&staged)?
+            .set_modified(mtime)
+            .with_context(|| format!("Failed to set the mtime of '{}'", entry.target_file_name))?;
+    }
+
+    // Set last, a read-only mode would keep the mtime from being written
+    let permissions = match (entry.target_file_mode, source) {
+        #[cfg(unix)]
+        (Some(mode), _) => Some(fs::Permissions::from_mode(mode)),
+        (_, Some(source)) => Some(source.permissions()),
+        _ => None,
+    };
+    if let Some(permissions) = permissions {
+        fs::set_permissions(&staged, permissions).with_context(|| {
+            format!(
+                "Failed to set the permissions of '{}'",
+                entry.target_file_name
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Rejects entries whose source, target or patch file would end up outside the game or patch directory
+pub fn check_paths(diff_entries: &[DiffEntry], what: &str) -> Result<()> {
+    for entry in diff_entries {
+        if !entry.source_file_name.is_empty() {
+            safe_path::check(&entry.source_file_name, what)?;
+        }
+        safe_path::check(&entry.target_file_name, what)?;
+        safe_path::check(&entry.patch_file_name, what)?;
+    }
+
+    Ok(())
+}
+
+/// Files and directories in a package that describe the patch rather than being part of the game
+pub fn is_patch_metadata(name: &str) -> bool {
+    matches!(
+        name,
+        "hdifffiles.txt" | "hdiffmap.json" | "deletefiles.txt" | "ldiff"
+    ) || name.starts_with("manifest")
+}
+
+/// Applies the entry's hdiff to `source_file` and writes the result into the staging directory
+pub fn stage_patch(
+    source_file: &Path,
+    patch_path: &Path,
+    staging_dir: &Path,
+    entry: &DiffEntry,
+) -> Result<()> {
+    let patch_file = patch_path.join(&entry.patch_file_name);
+    if !patch_file.exists() {
+        bail!("Missing patch file: {}", patch_file.display());
+    }
+
+    let staged = staging_dir.join(&entry.target_file_name);
+    if let Some(parent) = staged.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    hdiffpatch_rs::patch_hdiff(source_file, &patch_file, &staged).map_err(|e| {
+        anyhow::anyhow!(e.to_string())
+            .context(format!("Failed to patch '{}'", entry.target_file_name))
+    })?;
+
+    Ok(())
+}
+
 pub struct PatchManager {
     game_path: PathBuf,
     patch_path: PathBuf,
@@ -181,9 +662,30 @@ impl PatchManager {
             })
     }
 
-    pub fn patch(&self, progress: &ProgressBar) -> Result<()> {
+    /// Names of the game files an ldiff manifest patches, the package itself only lists its chunks
+    pub fn manifest_files(data: &[u8]) -> Result<Vec<String>> {
+        Ldiff::asset_names(data)
+    }
+
+    /// Estimates what patching takes on disk from the package's metadata file alone.
+    /// Returns the size of the generated hdiff files, of the patched output and of its largest file
+    pub fn estimate_output(
+        game_path: &Path,
+        metadata: &str,
+        data: &[u8],
+    ) -> Result<(u64, u64, u64)> {
+        if metadata.starts_with("manifest") {
+            Ldiff::output_size(data)
+        } else {
+            let (patched, largest) =
+                Hdiff::output_size(game_path, metadata, &String::from_utf8_lossy(data))?;
+            Ok((0, patched, largest))
+        }
+    }
+
+    pub fn patch(&self, ctx: &PatchContext, progress: &ProgressBar) -> Result<()> {
         self.patcher
-            .start(&self.game_path, &self.patch_path, progress)
+            .start(&self.game_path, &self.patch_path, ctx, progress)
     }
 
     pub fn patcher_name(&self) -> &'static str {
@@ -0,0 +1,121 @@
+use std::{env, fs, path::Path, process};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{
+    app::HaTemp,
+    patchers::{self, PatchManager},
+    update_package::UpdatePackage,
+};
+
+#[derive(Serialize)]
+pub struct PlannedFile {
+    pub file: String,
+    pub size: u64,
+}
+
+/// What applying a package would do to the game
+#[derive(Serialize)]
+pub struct PackagePlan {
+    pub package: String,
+    pub patcher: &'static str,
+    pub patched: Vec<PlannedFile>,
+    pub added: Vec<PlannedFile>,
+    pub replaced: Vec<PlannedFile>,
+    pub deleted: Vec<PlannedFile>,
+}
+
+#[derive(Serialize)]
+pub struct UpdatePlan {
+    pub packages: Vec<PackagePlan>,
+    /// Estimated peak disk usage while the update runs
+    pub disk_usage: u64,
+}
+
+/// Works out the plan from the archive listing and its patch metadata, nothing is extracted into the game
+pub fn plan_package(package: &UpdatePackage, game_path: &Path) -> Result<PackagePlan> {
+    let entries = package.list()?;
+
+    // Only the metadata is extracted, into the system's temp directory
+    let metadata_dir = HaTemp::new(
+        env::temp_dir()
+            .join("hdiff-apply")
+            .join(format!("plan-{}", process::id())),
+    )?;
+    for entry in entries
+        .iter()
+        .filter(|entry| !entry.is_dir && !entry.path.contains('/'))
+        .filter(|entry| patchers::is_patch_metadata(&entry.path))
+    {
+        fs::write(
+            metadata_dir.join(&entry.path),
+            package.read_file(&entry.path)?,
+        )
+        .with_context(|| format!("Failed to read '{}' from the package", entry.path))?;
+    }
+
+    let patcher = PatchManager::create_patcher(&metadata_dir)?;
+    let diff_entries = patcher.load_entries(&metadata_dir)?;
+    let deletions = patcher.planned_deletions(&metadata_dir, &diff_entries)?;
+
+    let game_size = |name: &str| fs::metadata(game_path.join(name)).map(|m| m.len());
+
+    let mut patched = Vec::new();
+    let mut added = Vec::new();
+    for entry in &diff_entries {
+        let file = PlannedFile {
+            file: entry.target_file_name.clone(),
+            size: match entry.target_file_size {
+                0 => game_size(&entry.target_file_name).unwrap_or(0),
+                size => size,
+            },
+        };
+        if entry.source_file_name.is_empty() {
+            added.push(file);
+        } else {
+            patched.push(file);
+        }
+    }
+
+    let mut replaced = Vec::new();
+    for entry in entries.iter().filter(|entry| !entry.is_dir) {
+        if entry.path.split('/').any(patchers::is_patch_metadata) || entry.path.ends_with(".hdiff")
+        {
+            continue;
+        }
+
+        let file = PlannedFile {
+            file: entry.path.clone(),
+            size: entry.size,
+        };
+        if game_path.join(&entry.path).exists() {
+            replaced.push(file);
+        } else {
+            added.push(file);
+        }
+    }
+
+    // Files that are already gone have nothing to delete
+    let mut deleted: Vec<_> = deletions
+        .into_iter()
+        .filter_map(|file| {
+            let size = game_size(&file).ok()?;
+            Some(PlannedFile { file, size })
+        })
+        .collect();
+
+    for files in [&mut patched, &mut added, &mut replaced, &mut deleted] {
+        files.sort_by(|a, b| a.file.cmp(&b.file));
+        files.dedup_by(|a, b| a.file == b.file);
+    }
+
+    Ok(PackagePlan {
+        package: package.name.clone(),
+        patcher: patcher.name(),
+        patched,
+        added,
+        replaced,
+        deleted,
+    })
+}
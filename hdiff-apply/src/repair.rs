@@ -0,0 +1,233 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    path::Path,
+};
+
+use anyhow::Result;
+use indicatif::ProgressBar;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::{
+    app::HaTemp,
+    cancel::CancelToken,
+    hash_cache::HashCache,
+    patchers::{self, PatchManager},
+    transaction::Transaction,
+    types::{CustomDiffMap, DiffEntry},
+    update_package::UpdatePackage,
+    verify,
+};
+
+pub enum FixMethod {
+    FullFile,
+    Patch { source: String },
+}
+
+pub struct Fix {
+    pub file: String,
+    pub package: String,
+    pub method: FixMethod,
+}
+
+pub struct RepairOutcome {
+    pub fixed: Vec<Fix>,
+    pub unresolved: Vec<String>,
+}
+
+/// Looks through every package for something that rebuilds the broken files, stages what it finds
+/// and commits it once all of the staged output matches `pkg_version`
+pub fn repair(
+    game_path: &Path,
+    work_dir: &Path,
+    archives: &[UpdatePackage],
+    broken: Vec<CustomDiffMap>,
+    cache: &HashCache,
+    progress: &ProgressBar,
+    cancel: &CancelToken,
+) -> Result<RepairOutcome> {
+    let tx = Transaction::new(game_path, work_dir)?;
+    let staging_dir = tx.staging_dir();
+    let mut pending: BTreeMap<String, CustomDiffMap> = broken
+        .into_iter()
+        .map(|entry| (entry.remote_name.clone(), entry))
+        .collect();
+    let mut fixed = Vec::new();
+    let mut staged = Vec::new();
+
+    for package in archives {
+        if pending.is_empty() {
+            break;
+        }
+
+        progress.unset_length();
+        progress.set_message("Extracting archive");
+        let extracted = HaTemp::new(work_dir.join(".ha-extracted"))?;
+        package.extract(&extracted, cancel)?;
+
+        for file in stage_full_files(&extracted, staging_dir, &pending, cancel)? {
+            staged.extend(pending.remove(&file));
+            fixed.push(Fix {
+                file,
+                package: package.name.clone(),
+                method: FixMethod::FullFile,
+            });
+        }
+
+        // Packages without patch metadata can still provide full files
+        let Ok(patcher) = PatchManager::create_patcher(&extracted) else {
+            continue;
+        };
+        let diff_entries = patcher.prepare(&extracted, cancel, progress)?;
+
+        for (file, source) in stage_patches(
+            game_path,
+            &extracted,
+            staging_dir,
+            &diff_entries,
+            &pending,
+            cancel,
+        )? {
+            staged.extend(pending.remove(&file));
+            fixed.push(Fix {
+                file,
+                package: package.name.clone(),
+                method: FixMethod::Patch { source },
+            });
+        }
+    }
+
+    cancel.check()?;
+
+    // Everything staged was already checked against pkg_version
+    for expected in &staged {
+        tx.replace(
+            staging_dir.join(&expected.remote_name),
+            &expected.remote_name,
+            &expected.md5,
+        );
+    }
+    tx.commit(cache, progress)?;
+
+    Ok(RepairOutcome {
+        fixed,
+        unresolved: pending.into_keys().collect(),
+    })
+}
+
+/// Moves full copies of broken files out of the extracted package when they match `pkg_version`
+fn stage_full_files(
+    extracted: &Path,
+    staging_dir: &Path,
+    pending: &BTreeMap<String, CustomDiffMap>,
+    cancel: &CancelToken,
+) -> Result<Vec<String>> {
+    pending
+        .values()
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|expected| -> Result<Option<String>> {
+            cancel.check()?;
+            let candidate = extracted.join(&expected.remote_name);
+            if !verify::check_file(&candidate, expected.file_size, &expected.md5)?.is_ok() {
+                return Ok(None);
+            }
+
+            let staged = staging_dir.join(&expected.remote_name);
+            if let Some(parent) = staged.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(&candidate, &staged)?;
+
+            Ok(Some(expected.remote_name.clone()))
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(|files| files.into_iter().flatten().collect())
+}
+
+/// Rebuilds broken files from hdiffs whose source is still intact.
+/// Returns the repaired files along with the source each one was built from
+fn stage_patches(
+    game_path: &Path,
+    patch_path: &Path,
+    staging_dir: &Path,
+    diff_entries: &[DiffEntry],
+    pending: &BTreeMap<String, CustomDiffMap>,
+    cancel: &CancelToken,
+) -> Result<Vec<(String, String)>> {
+    // Only the first candidate per file is used, later packages get a chance if it fails
+    let mut candidates = BTreeMap::new();
+    for entry in diff_entries {
+        if let Some(expected) = pending.get(&entry.target_file_name) {
+            cancel.check()?;
+            if !source_is_usable(game_path, entry, pending)? {
+                continue;
+            }
+            candidates
+                .entry(&entry.target_file_name)
+                .or_insert((entry, expected));
+        }
+    }
+
+    candidates
+        .into_values()
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|(entry, expected)| -> Result<Option<(String, String)>> {
+            cancel.check()?;
+            let source_file = if entry.source_file_name.is_empty() {
+                let empty = staging_dir.join(format!("{}.empty", entry.target_file_name));
+                if let Some(parent) = empty.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                File::create(&empty)?;
+                empty
+            } else {
+                game_path.join(&entry.source_file_name)
+            };
+
+            let staged = staging_dir.join(&entry.target_file_name);
+            let result = patchers::stage_patch(&source_file, patch_path, staging_dir, entry);
+            if entry.source_file_name.is_empty() {
+                let _ = fs::remove_file(&source_file);
+            }
+
+            // A patch that fails or produces the wrong output just means this package can't help
+            if result.is_err()
+                || !verify::check_file(&staged, expected.file_size, &expected.md5)?.is_ok()
+            {
+                let _ = fs::remove_file(&staged);
+                return Ok(None);
+            }
+
+            Ok(Some((
+                entry.target_file_name.clone(),
+                entry.source_file_name.clone(),
+            )))
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(|files| files.into_iter().flatten().collect())
+}
+
+fn source_is_usable(
+    game_path: &Path,
+    entry: &DiffEntry,
+    pending: &BTreeMap<String, CustomDiffMap>,
+) -> Result<bool> {
+    if entry.source_file_name.is_empty() {
+        return Ok(true);
+    }
+
+    if entry.source_file_md5.is_empty() {
+        // Without a recorded hash the output check decides, as long as the source isn't known bad
+        return Ok(game_path.join(&entry.source_file_name).is_file()
+            && !pending.contains_key(&entry.source_file_name));
+    }
+
+    Ok(verify::check_file(
+        &game_path.join(&entry.source_file_name),
+        entry.source_file_size,
+        &entry.source_file_md5,
+    )?
+    .is_ok())
+}
@@ -0,0 +1,67 @@
+use std::path::Path;
+
+use anyhow::{Result, bail};
+
+/// Makes sure a file name from patch metadata stays inside the directory it's joined onto.
+/// `what` names where the entry came from so a rejected package can be told apart
+pub fn check(name: &str, what: &str) -> Result<()> {
+    let reason = if name.is_empty() {
+        "the name is empty"
+    } else if name.starts_with(['/', '\\']) || Path::new(name).is_absolute() {
+        "absolute paths aren't allowed"
+    } else if name.contains(':') {
+        "drive prefixes aren't allowed"
+    } else if name.split(['/', '\\']).any(|component| component == "..") {
+        "it points outside the directory"
+    } else {
+        return Ok(());
+    };
+
+    bail!("Rejected {} entry '{}': {}", what, name, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+
+    #[test]
+    fn accepts_relative_names() {
+        for name in [
+            "StarRail.exe",
+            "StarRail_Data/StreamingAssets/a.block",
+            "StarRail_Data\\Plugins\\x.dll",
+            "a..b/c.txt",
+            "./file",
+        ] {
+            assert!(check(name, "test").is_ok(), "{name}");
+        }
+    }
+
+    #[test]
+    fn rejects_parent_components() {
+        for name in ["..", "../x", "a/../../x", "a\\..\\x", "a/.."] {
+            assert!(check(name, "test").is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        for name in ["/etc/passwd", "\\Windows\\x.dll", ""] {
+            assert!(check(name, "test").is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn rejects_drive_letters() {
+        for name in ["C:\\Windows\\x.dll", "C:x", "c:/x", "Z:"] {
+            assert!(check(name, "test").is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn rejects_unc_paths() {
+        for name in ["\\\\server\\share\\x", "//server/share/x", "\\\\?\\C:\\x"] {
+            assert!(check(name, "test").is_err(), "{name}");
+        }
+    }
+}
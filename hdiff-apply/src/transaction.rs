@@ -0,0 +1,1068 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Write},
+    mem,
+    path::{Path, PathBuf},
+    slice,
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result, bail};
+use indicatif::ProgressBar;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+
+use crate::{app::HaTemp, backup, hash_cache::HashCache, safe_path, verify};
+
+const JOURNAL_DIR: &str = ".ha-journal";
+const JOURNAL_FILE: &str = "journal.json";
+const ORIGINALS_DIR: &str = "originals";
+/// Replacements [`Transaction::apply_now`] made, one per line and written before the file is moved
+const IN_PLACE_LOG: &str = "in-place.jsonl";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    /// Move a verified file over `target`, a file it replaces is kept in the journal until the commit is done
+    Replace {
+        source: PathBuf,
+        target: String,
+        #[serde(default)]
+        md5: String,
+        #[serde(default)]
+        existed: bool,
+    },
+    Delete {
+        target: String,
+    },
+}
+
+impl Operation {
+    fn target(&self) -> &str {
+        match self {
+            Operation::Replace { target, .. } | Operation::Delete { target } => target,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Journal {
+    operations: Vec<Operation>,
+    /// The commit restores the newest backup, the older ones still fit the game once it's finished
+    #[serde(default)]
+    keeps_backups: bool,
+}
+
+/// Collects every change an update makes to the game so they can be committed through a write-ahead journal.
+/// Until [`Transaction::commit`] runs nothing in the game directory is touched
+pub struct Transaction {
+    game_path: PathBuf,
+    staging_root: HaTemp,
+    staging_dir: PathBuf,
+    step: usize,
+    planned: Mutex<Planned>,
+    in_place: Mutex<InPlace>,
+    backup: Option<(String, usize)>,
+    keeps_backups: bool,
+}
+
+#[derive(Default)]
+struct Planned {
+    /// Operations superseded by a later step are left as `None`
+    operations: Vec<Option<Operation>>,
+    /// The step and index of the newest operation for each target
+    latest: HashMap<String, (usize, usize)>,
+}
+
+/// Replacements already made in the game directory, they're undone unless the transaction commits
+#[derive(Default)]
+struct InPlace {
+    operations: Vec<Operation>,
+    log: Option<File>,
+}
+
+/// A game file as the planned operations leave it
+pub enum Current {
+    /// Untouched, it's whatever is in the game directory
+    Game,
+    Staged {
+        path: PathBuf,
+        md5: String,
+    },
+    Deleted,
+}
+
+impl Transaction {
+    /// Stages in `work_dir`, which may be on another filesystem than the game
+    pub fn new(game_path: &Path, work_dir: &Path) -> Result<Self> {
+        let staging_root = HaTemp::new(work_dir.join(".ha-staging"))?;
+        let staging_dir = staging_root.join("0");
+        fs::create_dir_all(&staging_dir)?;
+
+        Ok(Self {
+            game_path: game_path.to_path_buf(),
+            staging_root,
+            staging_dir,
+            step: 0,
+            planned: Mutex::new(Planned::default()),
+            in_place: Mutex::new(InPlace::default()),
+            backup: None,
+            keeps_backups: false,
+        })
+    }
+
+    /// Keeps what the commit replaces or deletes as a backup of `package`, along with up to `keep` older ones
+    pub fn backup(&mut self, package: &str, keep: usize) {
+        self.backup = (keep > 0).then(|| (package.to_string(), keep));
+    }
+
+    /// Leaves the existing backups alone when the commit doesn't store one, for restoring the newest
+    /// of them. Any other change to the game would have them restore a mix of old and new files
+    pub fn keep_backups(&mut self) {
+        self.keeps_backups = true;
+    }
+
+    /// Starts planning the next package of a chain in a staging directory of its own.
+    /// Its operations build on what the earlier steps planned and override them
+    pub fn next_step(&mut self) -> Result<()> {
+        self.step += 1;
+        self.staging_dir = self.staging_root.join(self.step.to_string());
+        fs::create_dir_all(&self.staging_dir)?;
+        Ok(())
+    }
+
+    pub fn staging_dir(&self) -> &Path {
+        &self.staging_dir
+    }
+
+    /// Plans moving `source` into the game as `target`, `md5` is recorded in the hash cache afterward
+    pub fn replace(&self, source: PathBuf, target: &str, md5: &str) {
+        self.plan(Operation::Replace {
+            source,
+            target: target.to_string(),
+            md5: md5.to_string(),
+            existed: false,
+        });
+    }
+
+    pub fn delete(&self, target: &str) {
+        self.plan(Operation::Delete {
+            target: target.to_string(),
+        });
+    }
+
+    fn plan(&self, operation: Operation) {
+        let mut planned = self.planned.lock().unwrap();
+        let target = operation.target().to_string();
+
+        // Within one step a second operation on the same file is a conflict the commit reports
+        if let Some(&(step, index)) = planned.latest.get(&target)
+            && step != self.step
+        {
+            planned.operations[index] = None;
+        }
+
+        let index = planned.operations.len();
+        planned.operations.push(Some(operation));
+        planned.latest.insert(target, (self.step, index));
+    }
+
+    /// Moves `source` into the game as `target` right away instead of at the commit, the file it
+    /// replaces is kept in the journal until [`Transaction::release_original`] so the move can still
+    /// be undone. Only one file's worth of space is needed on top of the game, but the game is
+    /// changed before the update is complete
+    pub fn apply_now(&self, source: PathBuf, target: &str, md5: &str) -> Result<()> {
+        let target_file = self.game_path.join(target);
+        let operation = Operation::Replace {
+            source,
+            target: target.to_string(),
+            md5: md5.to_string(),
+            existed: target_file.exists(),
+        };
+        check_targets(&self.game_path, slice::from_ref(&operation))?;
+
+        let mut in_place = self.in_place.lock().unwrap();
+        if in_place
+            .operations
+            .iter()
+            .any(|applied| applied.target() == target)
+        {
+            bail!("'{}' is written more than once by this update", target);
+        }
+
+        let log = match &mut in_place.log {
+            Some(log) => log,
+            log => {
+                // Nothing replaced in place is backed up
+                backup::discard(&self.game_path)?;
+                log.insert(create_in_place_log(&self.game_path)?)
+            }
+        };
+        let mut line = serde_json::to_vec(&operation)?;
+        line.push(b'\n');
+        log.write_all(&line)?;
+        log.sync_data()?;
+
+        // Recorded before anything moves, so a failure halfway is undone like the rest
+        in_place.operations.push(operation);
+        let Some(Operation::Replace {
+            source, existed, ..
+        }) = in_place.operations.last()
+        else {
+            unreachable!();
+        };
+
+        if *existed {
+            let original = self
+                .game_path
+                .join(JOURNAL_DIR)
+                .join(ORIGINALS_DIR)
+                .join(target);
+            move_file(&target_file, &original)?;
+        }
+        move_file(source, &target_file)
+    }
+
+    /// Where the version of `name` from before [`Transaction::apply_now`] replaced it is kept
+    pub fn original_of(&self, name: &str) -> Option<PathBuf> {
+        let in_place = self.in_place.lock().unwrap();
+        in_place
+            .operations
+            .iter()
+            .find_map(|operation| match operation {
+                Operation::Replace {
+                    target,
+                    existed: true,
+                    ..
+                } if target == name => Some(
+                    self.game_path
+                        .join(JOURNAL_DIR)
+                        .join(ORIGINALS_DIR)
+                        .join(name),
+                ),
+                _ => None,
+            })
+    }
+
+    /// Drops the file [`Transaction::apply_now`] replaced with `name`, its replacement stays in the
+    /// game from now on even when the transaction doesn't commit
+    pub fn release_original(&self, name: &str) -> Result<()> {
+        if let Some(original) = self.original_of(name)
+            && original.exists()
+        {
+            fs::remove_file(&original)
+                .with_context(|| format!("Failed to remove '{}'", original.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Where `name` is found once everything planned so far is committed
+    pub fn current(&self, name: &str) -> Current {
+        let planned = self.planned.lock().unwrap();
+        let operation = planned
+            .latest
+            .get(name)
+            .and_then(|&(_, index)| planned.operations[index].as_ref());
+
+        match operation {
+            Some(Operation::Replace { source, md5, .. }) => Current::Staged {
+                path: source.clone(),
+                md5: md5.clone(),
+            },
+            Some(Operation::Delete { .. }) => Current::Deleted,
+            None => Current::Game,
+        }
+    }
+
+    pub fn commit(mut self, cache: &HashCache, progress: &ProgressBar) -> Result<()> {
+        let planned = mem::take(self.planned.get_mut().unwrap());
+        let mut operations = self.in_place.get_mut().unwrap().operations.clone();
+        let applied = operations.len();
+        operations.extend(planned.operations.into_iter().flatten());
+        if operations.is_empty() {
+            return Ok(());
+        }
+
+        check_conflicts(&mut operations)?;
+        let mut operations = operations.split_off(applied);
+        check_targets(&self.game_path, &operations)?;
+
+        // Everything else fits, so the files replaced in place are final and only the rest is journaled
+        let in_place = mem::take(self.in_place.get_mut().unwrap());
+        drop(in_place.log);
+        for operation in &in_place.operations {
+            if let Operation::Replace { target, md5, .. } = operation {
+                cache.record(target, md5)?;
+            }
+        }
+        if !in_place.operations.is_empty() {
+            // Along with the log go the originals still kept for later entries
+            remove_journal(&self.game_path)?;
+        }
+        if operations.is_empty() {
+            return cache.save();
+        }
+
+        for operation in &mut operations {
+            if let Operation::Replace {
+                target, existed, ..
+            } = operation
+            {
+                *existed = self.game_path.join(&*target).exists();
+            }
+        }
+
+        if self.backup.is_none() && !self.keeps_backups {
+            backup::discard(&self.game_path)?;
+        }
+
+        let journal = Journal {
+            operations,
+            keeps_backups: self.keeps_backups,
+        };
+        write_journal(&self.game_path, &journal)?;
+
+        progress.set_message("Merging files");
+        progress.set_position(0);
+        progress.set_length(journal.operations.len() as _);
+
+        // Deletions run once everything is in place, the same order they had before the journal
+        let (replaces, deletes): (Vec<&Operation>, Vec<&Operation>) = journal
+            .operations
+            .iter()
+            .partition(|operation| matches!(operation, Operation::Replace { .. }));
+
+        let originals = self.game_path.join(JOURNAL_DIR).join(ORIGINALS_DIR);
+        let apply = |operations: &[&Operation]| {
+            operations
+                .par_iter()
+                .try_for_each(|operation| -> Result<()> {
+                    let target_file = self.game_path.join(operation.target());
+                    let original = originals.join(operation.target());
+
+                    match operation {
+                        Operation::Replace {
+                            source, existed, ..
+                        } => {
+                            if *existed {
+                                move_file(&target_file, &original)?;
+                            }
+                            move_file(source, &target_file)?;
+                        }
+                        Operation::Delete { .. } => {
+                            if target_file.exists() {
+                                move_file(&target_file, &original)?;
+                            }
+                        }
+                    }
+
+                    progress.inc(1);
+                    Ok(())
+                })
+        };
+
+        let result = apply(&replaces).and_then(|_| apply(&deletes));
+        if result.is_err() {
+            // The journal finishes the commit with the staged files that weren't moved in yet
+            self.staging_root.keep();
+        }
+        result.with_context(|| {
+            format!(
+                "Commit failed, run hdiff-apply again to finish or roll back the update in '{}'",
+                self.game_path.display()
+            )
+        })?;
+
+        for operation in &journal.operations {
+            if let Operation::Replace { target, md5, .. } = operation {
+                cache.record(target, md5)?;
+            }
+        }
+
+        // The update is complete once the journal is gone, a crash after that loses the backup
+        close_journal(&self.game_path)?;
+        if let Some((package, keep)) = &self.backup {
+            backup::store(
+                &self.game_path,
+                &originals,
+                package,
+                journal.operations,
+                *keep,
+            )?;
+        }
+
+        remove_journal(&self.game_path)?;
+        cache.save()
+    }
+}
+
+impl Drop for Transaction {
+    /// A transaction that never committed takes back what it replaced in place and didn't release
+    fn drop(&mut self) {
+        let in_place = mem::take(self.in_place.get_mut().unwrap());
+        if in_place.operations.is_empty() {
+            return;
+        }
+        drop(in_place.log);
+
+        let result: Result<()> = try {
+            for operation in &in_place.operations {
+                put_back(&self.game_path, operation)?;
+            }
+            remove_journal(&self.game_path)?;
+        };
+        if let Err(e) = result {
+            eprintln!(
+                "Failed to undo the files already replaced, run hdiff-apply again to roll them back: {e:#}"
+            );
+        }
+    }
+}
+
+/// Whether a commit or files replaced in place left their journal behind, `recover` finishes or
+/// rolls it back on the next run
+pub fn has_journal(game_path: &Path) -> bool {
+    let dir = game_path.join(JOURNAL_DIR);
+    dir.join(JOURNAL_FILE).exists() || dir.join(IN_PLACE_LOG).exists()
+}
+
+/// Finishes or rolls back a commit that was interrupted, e.g. by a crash or power loss.
+/// The commit is finished when every file it still has to move in is there, otherwise it's rolled back.
+/// Files replaced in place by an update that never got to its commit are put back while their originals are kept
+pub fn recover(game_path: &Path, work_dir: &Path) -> Result<Option<Recovery>> {
+    let journal_dir = game_path.join(JOURNAL_DIR);
+    let journal_path = journal_dir.join(JOURNAL_FILE);
+    let log_path = journal_dir.join(IN_PLACE_LOG);
+
+    let (operations, can_finish, in_place) = if journal_path.exists() {
+        let data = fs::read_to_string(&journal_path)
+            .with_context(|| format!("Failed to read '{}'", journal_path.display()))?;
+        let journal: Journal = serde_json::from_str(&data)
+            .with_context(|| format!("Failed to parse '{}'", journal_path.display()))?;
+
+        // A replacement that was made moved its original aside first, a target that's still
+        // there without one is the old file and its staged replacement is lost
+        let originals = journal_dir.join(ORIGINALS_DIR);
+        let can_finish = journal.operations.iter().all(|operation| match operation {
+            Operation::Replace {
+                source,
+                target,
+                existed,
+                ..
+            } => {
+                source.exists()
+                    || (game_path.join(target).exists()
+                        && (!*existed || originals.join(target).exists()))
+            }
+            Operation::Delete { .. } => true,
+        });
+        // A finished commit that was meant to store a backup never got to, the older ones don't fit anymore
+        if can_finish && !journal.keeps_backups {
+            backup::discard(game_path)?;
+        }
+        (journal.operations, can_finish, false)
+    } else if log_path.exists() {
+        (read_in_place_log(&log_path)?, false, true)
+    } else {
+        // A commit that got as far as removing its journal is complete, only the originals are left.
+        // While they're there its backup wasn't stored
+        if journal_dir.join(ORIGINALS_DIR).exists() {
+            backup::discard(game_path)?;
+        }
+        let _ = fs::remove_dir_all(journal_dir);
+        return Ok(None);
+    };
+
+    let (replaces, deletes): (Vec<&Operation>, Vec<&Operation>) = operations
+        .iter()
+        .partition(|operation| matches!(operation, Operation::Replace { .. }));
+
+    for operation in replaces.into_iter().chain(deletes) {
+        if can_finish {
+            finish(game_path, operation)?;
+        } else if in_place {
+            put_back(game_path, operation)?;
+        } else {
+            undo(game_path, operation)?;
+        }
+    }
+
+    // With --keep-mtime a new file can have the size and mtime of the one it replaced, so the
+    // cached hashes of every file the commit touched can't be trusted either way
+    let cache = HashCache::load(game_path);
+    for operation in &operations {
+        cache.record(operation.target(), "")?;
+    }
+    cache.save()?;
+
+    remove_journal(game_path)?;
+    let _ = fs::remove_dir_all(work_dir.join(".ha-staging"));
+    let _ = fs::remove_dir_all(work_dir.join(".ha-extracted"));
+
+    Ok(Some(if can_finish {
+        Recovery::Finished(operations.len())
+    } else {
+        Recovery::RolledBack(operations.len())
+    }))
+}
+
+fn finish(game_path: &Path, operation: &Operation) -> Result<()> {
+    let target_file = game_path.join(operation.target());
+
+    match operation {
+        Operation::Replace { source, .. } => {
+            if source.exists() {
+                move_file(source, &target_file)?;
+            }
+        }
+        Operation::Delete { .. } => {
+            if target_file.exists() {
+                fs::remove_file(&target_file)
+                    .with_context(|| format!("Failed to delete '{}'", target_file.display()))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn undo(game_path: &Path, operation: &Operation) -> Result<()> {
+    let target_file = game_path.join(operation.target());
+    let original = game_path
+        .join(JOURNAL_DIR)
+        .join(ORIGINALS_DIR)
+        .join(operation.target());
+
+    match operation {
+        Operation::Replace {
+            source, existed, ..
+        } => {
+            if original.exists() {
+                move_file(&original, &target_file)?;
+            } else if !*existed && !source.exists() && target_file.exists() {
+                // The file is new in this update and was already moved in
+                fs::remove_file(&target_file)
+                    .with_context(|| format!("Failed to remove '{}'", target_file.display()))?;
+            }
+        }
+        Operation::Delete { .. } => {
+            if original.exists() {
+                move_file(&original, &target_file)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Undoes a replacement made in place while its original is still kept, moving the file in is a
+/// single rename so a target that's there without its original is complete
+fn put_back(game_path: &Path, operation: &Operation) -> Result<()> {
+    let original = game_path
+        .join(JOURNAL_DIR)
+        .join(ORIGINALS_DIR)
+        .join(operation.target());
+    if original.exists() {
+        move_file(&original, &game_path.join(operation.target()))?;
+    }
+    Ok(())
+}
+
+pub enum Recovery {
+    Finished(usize),
+    RolledBack(usize),
+}
+
+/// Makes sure every file can be moved in before anything is, a directory in the way would fail halfway through
+fn check_targets(game_path: &Path, operations: &[Operation]) -> Result<()> {
+    for operation in operations {
+        safe_path::check(operation.target(), "planned")?;
+
+        let Operation::Replace { source, target, .. } = operation else {
+            continue;
+        };
+
+        if !source.is_file() {
+            bail!("'{}' has nothing to replace it with", target);
+        }
+
+        let target_file = game_path.join(target);
+        if target_file.is_dir() {
+            bail!("Can't replace '{}', it's a directory", target);
+        }
+
+        if let Some(blocked) = target_file
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| *dir != game_path)
+            .find(|dir| dir.exists() && !dir.is_dir())
+        {
+            bail!(
+                "Can't write '{}', '{}' is a file",
+                target,
+                blocked.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Makes sure no file is written twice or deleted after being written, repeated deletions are merged
+fn check_conflicts(operations: &mut Vec<Operation>) -> Result<()> {
+    let mut replaced = HashSet::new();
+    for operation in operations.iter() {
+        if let Operation::Replace { target, .. } = operation
+            && !replaced.insert(target.as_str())
+        {
+            bail!("'{}' is written more than once by this update", target);
+        }
+    }
+
+    let mut deleted = HashSet::new();
+    for operation in operations.iter() {
+        if let Operation::Delete { target } = operation
+            && replaced.contains(target.as_str())
+        {
+            bail!("'{}' is both written and deleted by this update", target);
+        }
+    }
+
+    operations.retain(|operation| match operation {
+        Operation::Delete { target } => deleted.insert(target.clone()),
+        Operation::Replace { .. } => true,
+    });
+
+    Ok(())
+}
+
+fn write_journal(game_path: &Path, journal: &Journal) -> Result<()> {
+    let dir = game_path.join(JOURNAL_DIR);
+    fs::create_dir_all(&dir)?;
+
+    // Written next to the journal and renamed over it, a crash halfway leaves no truncated journal behind
+    let path = dir.join(JOURNAL_FILE);
+    let temp_path = path.with_extension("tmp");
+    let mut file = File::create(&temp_path)
+        .with_context(|| format!("Failed to create journal '{}'", temp_path.display()))?;
+    file.write_all(&serde_json::to_vec(journal)?)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&temp_path, &path)
+        .with_context(|| format!("Failed to write journal '{}'", path.display()))?;
+
+    // Make sure the journal's directory entry is on disk too before anything is moved
+    #[cfg(unix)]
+    File::open(&dir)?.sync_all()?;
+
+    Ok(())
+}
+
+fn create_in_place_log(game_path: &Path) -> Result<File> {
+    let dir = game_path.join(JOURNAL_DIR);
+    fs::create_dir_all(&dir)?;
+
+    let path = dir.join(IN_PLACE_LOG);
+    let file = File::options()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to create journal '{}'", path.display()))?;
+
+    #[cfg(unix)]
+    File::open(&dir)?.sync_all()?;
+
+    Ok(file)
+}
+
+/// The replacements logged so far, a line cut short by a crash is the one that never got to move anything
+fn read_in_place_log(path: &Path) -> Result<Vec<Operation>> {
+    let file = File::open(path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+
+    let mut operations = Vec::new();
+    for line in BufReader::new(file).lines() {
+        match serde_json::from_str(&line?) {
+            Ok(operation) => operations.push(operation),
+            Err(_) => break,
+        }
+    }
+
+    Ok(operations)
+}
+
+/// Removes the journal but keeps the originals. The in-place log goes first, once it's gone
+/// a remaining journal still finishes the commit instead of rolling back what was replaced in place
+fn close_journal(game_path: &Path) -> Result<()> {
+    let dir = game_path.join(JOURNAL_DIR);
+    for file in [IN_PLACE_LOG, JOURNAL_FILE] {
+        match fs::remove_file(dir.join(file)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(e).context("Failed to remove the commit journal");
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn remove_journal(game_path: &Path) -> Result<()> {
+    close_journal(game_path)?;
+    let _ = fs::remove_dir_all(game_path.join(JOURNAL_DIR));
+    Ok(())
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+    }
+
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => copy_across(from, to),
+        result => result.with_context(|| format!("Failed to move '{}' into place", to.display())),
+    }
+}
+
+/// Moves a file between filesystems. The copy is synced and checked against the original
+/// before it replaces `to`, and only then is the original removed
+fn copy_across(from: &Path, to: &Path) -> Result<()> {
+    let mut temp_name = to.as_os_str().to_owned();
+    temp_name.push(".ha-tmp");
+    let temp = PathBuf::from(temp_name);
+
+    let result = (|| -> Result<()> {
+        let mut source = File::open(from)?;
+        let mut copy = File::create(&temp)?;
+        io::copy(&mut source, &mut copy)?;
+
+        let metadata = source.metadata()?;
+        copy.set_modified(metadata.modified()?)?;
+        copy.sync_all()?;
+        drop(copy);
+        fs::set_permissions(&temp, metadata.permissions())?;
+
+        if verify::file_md5(&temp)? != verify::file_md5(from)? {
+            bail!("the copy doesn't match the original");
+        }
+
+        fs::rename(&temp, to)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result.with_context(|| format!("Failed to copy '{}' into place", to.display()))?;
+
+    fs::remove_file(from)
+        .with_context(|| format!("Failed to remove '{}' after copying it", from.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory in the system temp directory, unique to the test
+    fn temp_game(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("hdiff-apply-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn replace(source: &Path, target: &str, existed: bool) -> Operation {
+        Operation::Replace {
+            source: source.to_path_buf(),
+            target: target.to_string(),
+            md5: String::new(),
+            existed,
+        }
+    }
+
+    fn delete(target: &str) -> Operation {
+        Operation::Delete {
+            target: target.to_string(),
+        }
+    }
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    fn read(path: &Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn conflicts_reject_double_writes() {
+        let source = Path::new("staged");
+        let mut operations = vec![
+            replace(source, "a.txt", true),
+            replace(source, "a.txt", true),
+        ];
+        assert!(check_conflicts(&mut operations).is_err());
+    }
+
+    #[test]
+    fn conflicts_reject_writing_and_deleting() {
+        let source = Path::new("staged");
+        let mut operations = vec![delete("a.txt"), replace(source, "a.txt", true)];
+        assert!(check_conflicts(&mut operations).is_err());
+    }
+
+    #[test]
+    fn conflicts_merge_repeated_deletes() {
+        let source = Path::new("staged");
+        let mut operations = vec![
+            delete("b.txt"),
+            replace(source, "a.txt", true),
+            delete("b.txt"),
+            delete("c.txt"),
+        ];
+        check_conflicts(&mut operations).unwrap();
+
+        let targets: Vec<&str> = operations.iter().map(Operation::target).collect();
+        assert_eq!(targets, ["b.txt", "a.txt", "c.txt"]);
+    }
+
+    #[test]
+    fn recover_without_journal() {
+        let game = temp_game("no-journal");
+        assert!(recover(&game, &game).unwrap().is_none());
+        fs::remove_dir_all(&game).unwrap();
+    }
+
+    #[test]
+    fn recover_finishes_journal() {
+        let game = temp_game("finish");
+        let originals = game.join(JOURNAL_DIR).join(ORIGINALS_DIR);
+        let staged = game.join("staged");
+
+        // a.txt was already moved in, b.txt is still staged, c.txt was moved aside to be deleted
+        write(&game.join("a.txt"), "new a");
+        write(&originals.join("a.txt"), "old a");
+        write(&staged.join("b.txt"), "new b");
+        write(&game.join("b.txt"), "old b");
+        write(&originals.join("c.txt"), "old c");
+        let operations = vec![
+            replace(&staged.join("a.txt"), "a.txt", true),
+            replace(&staged.join("b.txt"), "b.txt", true),
+            delete("c.txt"),
+        ];
+        write_journal(
+            &game,
+            &Journal {
+                operations,
+                keeps_backups: false,
+            },
+        )
+        .unwrap();
+
+        let recovery = recover(&game, &game).unwrap();
+        assert!(matches!(recovery, Some(Recovery::Finished(3))));
+        assert_eq!(read(&game.join("a.txt")), "new a");
+        assert_eq!(read(&game.join("b.txt")), "new b");
+        assert!(!game.join("c.txt").exists());
+        assert!(!game.join(JOURNAL_DIR).exists());
+
+        fs::remove_dir_all(&game).unwrap();
+    }
+
+    #[test]
+    fn recover_rolls_back_journal() {
+        let game = temp_game("roll-back");
+        let originals = game.join(JOURNAL_DIR).join(ORIGINALS_DIR);
+        let staged = game.join("staged");
+
+        // a.txt was moved aside but its replacement is gone, so the commit can't be finished
+        write(&originals.join("a.txt"), "old a");
+        write(&game.join("b.txt"), "new b");
+        write(&originals.join("c.txt"), "old c");
+        let operations = vec![
+            replace(&staged.join("a.txt"), "a.txt", true),
+            replace(&staged.join("b.txt"), "b.txt", false),
+            delete("c.txt"),
+        ];
+        write_journal(
+            &game,
+            &Journal {
+                operations,
+                keeps_backups: false,
+            },
+        )
+        .unwrap();
+
+        let recovery = recover(&game, &game).unwrap();
+        assert!(matches!(recovery, Some(Recovery::RolledBack(3))));
+        assert_eq!(read(&game.join("a.txt")), "old a");
+        assert!(!game.join("b.txt").exists());
+        assert_eq!(read(&game.join("c.txt")), "old c");
+        assert!(!game.join(JOURNAL_DIR).exists());
+
+        fs::remove_dir_all(&game).unwrap();
+    }
+
+    #[test]
+    fn recover_rolls_back_in_place_log() {
+        let game = temp_game("in-place");
+        let originals = game.join(JOURNAL_DIR).join(ORIGINALS_DIR);
+        let staged = game.join("staged");
+
+        // Three files were replaced in place and c.txt's original was already released,
+        // the crash cut the last line of the log short
+        write(&game.join("a.txt"), "new a");
+        write(&originals.join("a.txt"), "old a");
+        write(&game.join("b.txt"), "new b");
+        write(&game.join("c.txt"), "new c");
+        let mut log = create_in_place_log(&game).unwrap();
+        for operation in [
+            replace(&staged.join("a.txt"), "a.txt", true),
+            replace(&staged.join("b.txt"), "b.txt", false),
+            replace(&staged.join("c.txt"), "c.txt", true),
+        ] {
+            serde_json::to_writer(&mut log, &operation).unwrap();
+            writeln!(log).unwrap();
+        }
+        write!(log, "{{\"op\":\"repl").unwrap();
+        drop(log);
+
+        let recovery = recover(&game, &game).unwrap();
+        assert!(matches!(recovery, Some(Recovery::RolledBack(3))));
+        assert_eq!(read(&game.join("a.txt")), "old a");
+        assert_eq!(read(&game.join("b.txt")), "new b");
+        assert_eq!(read(&game.join("c.txt")), "new c");
+        assert!(!game.join(JOURNAL_DIR).exists());
+
+        fs::remove_dir_all(&game).unwrap();
+    }
+
+    #[test]
+    fn recover_rolls_back_replacement_that_never_ran() {
+        let game = temp_game("never-ran");
+        let originals = game.join(JOURNAL_DIR).join(ORIGINALS_DIR);
+        let staged = game.join("staged");
+
+        // a.txt was replaced, b.txt is still the old file and the staged one is gone
+        write(&game.join("a.txt"), "new a");
+        write(&originals.join("a.txt"), "old a");
+        write(&game.join("b.txt"), "old b");
+        let operations = vec![
+            replace(&staged.join("a.txt"), "a.txt", true),
+            replace(&staged.join("b.txt"), "b.txt", true),
+        ];
+        write_journal(
+            &game,
+            &Journal {
+                operations,
+                keeps_backups: false,
+            },
+        )
+        .unwrap();
+
+        let recovery = recover(&game, &game).unwrap();
+        assert!(matches!(recovery, Some(Recovery::RolledBack(2))));
+        assert_eq!(read(&game.join("a.txt")), "old a");
+        assert_eq!(read(&game.join("b.txt")), "old b");
+
+        fs::remove_dir_all(&game).unwrap();
+    }
+
+    #[test]
+    fn failed_commit_is_finished_by_recover() {
+        let game = temp_game("failed-commit");
+        write(&game.join("a.txt"), "old a");
+        write(&game.join("b.txt"), "old b");
+
+        let tx = Transaction::new(&game, &game).unwrap();
+        for name in ["a.txt", "b.txt"] {
+            let source = tx.staging_dir().join(name);
+            write(&source, &format!("new {}", &name[..1]));
+            tx.replace(source, name, "");
+        }
+
+        // A directory where b.txt's original goes makes moving it aside fail
+        let blocker = game.join(JOURNAL_DIR).join(ORIGINALS_DIR).join("b.txt");
+        write(&blocker.join("x"), "");
+        let cache = HashCache::load(&game);
+        assert!(tx.commit(&cache, &ProgressBar::hidden()).is_err());
+        assert!(has_journal(&game));
+        fs::remove_dir_all(&blocker).unwrap();
+
+        let recovery = recover(&game, &game).unwrap();
+        assert!(matches!(recovery, Some(Recovery::Finished(2))));
+        assert_eq!(read(&game.join("a.txt")), "new a");
+        assert_eq!(read(&game.join("b.txt")), "new b");
+        assert!(!game.join(".ha-staging").exists());
+
+        fs::remove_dir_all(&game).unwrap();
+    }
+
+    #[test]
+    fn dropped_transaction_keeps_released_files() {
+        let game = temp_game("released");
+        write(&game.join("a.txt"), "old a");
+        write(&game.join("b.txt"), "old b");
+
+        let tx = Transaction::new(&game, &game).unwrap();
+        for name in ["a.txt", "b.txt"] {
+            let source = tx.staging_dir().join(name);
+            write(&source, &format!("new {}", &name[..1]));
+            tx.apply_now(source, name, "").unwrap();
+        }
+        tx.release_original("a.txt").unwrap();
+        assert_eq!(read(&tx.original_of("b.txt").unwrap()), "old b");
+        drop(tx);
+
+        assert_eq!(read(&game.join("a.txt")), "new a");
+        assert_eq!(read(&game.join("b.txt")), "old b");
+        assert!(!game.join(JOURNAL_DIR).exists());
+
+        fs::remove_dir_all(&game).unwrap();
+    }
+
+    #[test]
+    fn commit_finalizes_files_replaced_in_place() {
+        let game = temp_game("in-place-commit");
+        write(&game.join("a.txt"), "old a");
+        write(&game.join("b.txt"), "old b");
+
+        let tx = Transaction::new(&game, &game).unwrap();
+        let source = tx.staging_dir().join("a.txt");
+        write(&source, "new a");
+        tx.apply_now(source, "a.txt", "").unwrap();
+        tx.delete("b.txt");
+
+        let cache = HashCache::load(&game);
+        tx.commit(&cache, &ProgressBar::hidden()).unwrap();
+        assert_eq!(read(&game.join("a.txt")), "new a");
+        assert!(!game.join("b.txt").exists());
+        assert!(!game.join(JOURNAL_DIR).exists());
+
+        fs::remove_dir_all(&game).unwrap();
+    }
+
+    #[test]
+    fn commit_without_backup_discards_backups() {
+        let game = temp_game("discard-backups");
+        write(&game.join("a.txt"), "old a");
+        write(&game.join(".ha-backups/0001/backup.json"), "{}");
+
+        let mut tx = Transaction::new(&game, &game).unwrap();
+        tx.keep_backups();
+        tx.delete("a.txt");
+        tx.commit(&HashCache::load(&game), &ProgressBar::hidden())
+            .unwrap();
+        assert!(game.join(".ha-backups").exists());
+
+        write(&game.join("a.txt"), "new a");
+        let tx = Transaction::new(&game, &game).unwrap();
+        tx.delete("a.txt");
+        tx.commit(&HashCache::load(&game), &ProgressBar::hidden())
+            .unwrap();
+        assert!(!game.join(".ha-backups").exists());
+
+        fs::remove_dir_all(&game).unwrap();
+    }
+}
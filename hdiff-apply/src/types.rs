@@ -1,3 +1,4 @@
+use anyhow::{Context, Result};
 use serde::Deserialize;
 
 #[derive(Deserialize, Debug, Default)]
@@ -14,6 +15,10 @@ pub struct DiffEntry {
     pub patch_file_name: String,
     pub patch_file_md5: String,
     pub patch_file_size: u64,
+
+    /// Optional metadata for the patched file, otherwise it's taken from the source
+    pub target_file_mode: Option<u32>,
+    pub target_file_mtime: Option<u64>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -21,8 +26,25 @@ pub struct HDiffMap {
     pub diff_map: Vec<DiffEntry>,
 }
 
+/// A line of `hdifffiles.txt` or `pkg_version`, the latter also carries the file's md5 and size
 #[derive(Deserialize, Debug)]
 pub struct CustomDiffMap {
     #[serde(rename = "remoteName")]
     pub remote_name: String,
+    #[serde(default)]
+    pub md5: String,
+    #[serde(rename = "fileSize", default)]
+    pub file_size: u64,
+}
+
+impl CustomDiffMap {
+    pub fn parse_lines(data: &str) -> Result<Vec<Self>> {
+        data.lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|line| {
+                serde_json::from_str::<Self>(line.trim())
+                    .with_context(|| format!("Failed to parse line: {}", line))
+            })
+            .collect()
+    }
 }
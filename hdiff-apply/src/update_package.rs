@@ -4,9 +4,9 @@ use std::{
 };
 
 use anyhow::{Context, Result};
-use seven_zip::SevenZip;
+use seven_zip::{ArchiveEntry, SevenZip};
 
-use crate::byte_convert::ByteConvert;
+use crate::{byte_convert::ByteConvert, cancel::CancelToken};
 
 #[derive(Debug)]
 pub struct UpdatePackage {
@@ -47,8 +47,21 @@ impl UpdatePackage {
         Ok(archives)
     }
 
-    pub fn extract(&self, game_path: &Path) -> Result<()> {
-        SevenZip::extract(&self.path, &game_path)?;
+    pub fn test(&self, cancel: &CancelToken) -> Result<()> {
+        SevenZip::test(&self.path, cancel.flag())?;
+        Ok(())
+    }
+
+    pub fn list(&self) -> Result<Vec<ArchiveEntry>> {
+        Ok(SevenZip::list(&self.path)?)
+    }
+
+    pub fn read_file(&self, name: &str) -> Result<Vec<u8>> {
+        Ok(SevenZip::read_file(&self.path, name)?)
+    }
+
+    pub fn extract(&self, game_path: &Path, cancel: &CancelToken) -> Result<()> {
+        SevenZip::extract(&self.path, &game_path, cancel.flag())?;
         Ok(())
     }
 }
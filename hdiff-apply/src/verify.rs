@@ -0,0 +1,247 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{BufReader, Read},
+    path::Path,
+};
+
+use anyhow::{Context, Result, bail};
+use indicatif::ProgressBar;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::Serialize;
+
+use crate::{cancel::CancelToken, hash_cache::HashCache, safe_path, types::CustomDiffMap};
+
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum FileState {
+    Ok,
+    Missing,
+    SizeMismatch { expected: u64, actual: u64 },
+    HashMismatch { expected: String, actual: String },
+}
+
+impl FileState {
+    pub fn is_ok(&self) -> bool {
+        matches!(self, FileState::Ok)
+    }
+
+    pub fn category(&self) -> &'static str {
+        match self {
+            FileState::Ok => "Ok",
+            FileState::Missing => "Missing",
+            FileState::SizeMismatch { .. } => "Wrong size",
+            FileState::HashMismatch { .. } => "Wrong hash",
+        }
+    }
+}
+
+impl fmt::Display for FileState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileState::Ok => write!(f, "ok"),
+            FileState::Missing => write!(f, "missing"),
+            FileState::SizeMismatch { expected, actual } => {
+                write!(f, "size mismatch (expected {expected}, got {actual})")
+            }
+            FileState::HashMismatch { expected, actual } => {
+                write!(f, "md5 mismatch (expected {expected}, got {actual})")
+            }
+        }
+    }
+}
+
+/// A file as described by patch metadata: its path relative to some root plus the expected size and md5
+pub struct ExpectedFile<'a> {
+    pub name: &'a str,
+    pub size: u64,
+    pub md5: &'a str,
+}
+
+#[derive(Serialize)]
+pub struct FileReport {
+    pub file: String,
+    #[serde(flatten)]
+    pub state: FileState,
+}
+
+#[derive(Serialize)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub failed: Vec<FileReport>,
+}
+
+/// Reads a file in the format of `pkg_version`, `version_file` is its name in `dir`
+pub fn load_pkg_version(dir: &Path, version_file: &str) -> Result<Vec<CustomDiffMap>> {
+    let path = dir.join(version_file);
+    let data = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read '{}'", path.display()))?;
+
+    let entries = CustomDiffMap::parse_lines(&data)
+        .with_context(|| format!("Failed to parse {}", version_file))?;
+
+    // A package can ship its own, so the names are as untrusted as any other patch metadata
+    for entry in &entries {
+        safe_path::check(&entry.remote_name, version_file)?;
+    }
+
+    Ok(entries)
+}
+
+/// Checks the whole install against its `pkg_version`
+pub fn verify_install(
+    game_path: &Path,
+    version_file: &str,
+    cache: &HashCache,
+    cancel: &CancelToken,
+    progress: &ProgressBar,
+) -> Result<VerifyReport> {
+    let entries = load_pkg_version(game_path, version_file)?;
+    let files: Vec<ExpectedFile> = entries
+        .iter()
+        .map(|entry| ExpectedFile {
+            name: &entry.remote_name,
+            size: entry.file_size,
+            md5: &entry.md5,
+        })
+        .collect();
+
+    let failed = check_files(game_path, &files, Some(cache), cancel, progress)?
+        .into_iter()
+        .map(|(file, state)| FileReport { file, state })
+        .collect();
+
+    Ok(VerifyReport {
+        checked: files.len(),
+        failed,
+    })
+}
+
+pub fn file_md5(path: &Path) -> Result<String> {
+    let file = File::open(path).with_context(|| format!("Failed to open '{}'", path.display()))?;
+    let mut reader = BufReader::with_capacity(HASH_BUFFER_SIZE, file);
+    let mut context = md5::Context::new();
+    let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
+
+    loop {
+        let read = reader
+            .read(&mut buffer)
+            .with_context(|| format!("Failed to read '{}'", path.display()))?;
+        if read == 0 {
+            break;
+        }
+        context.consume(&buffer[..read]);
+    }
+
+    Ok(format!("{:x}", context.finalize()))
+}
+
+/// Compares a file on disk against the size and md5 recorded in patch metadata.
+/// An empty `expected_md5` means the metadata doesn't carry a hash, so only existence is checked.
+pub fn check_file(path: &Path, expected_size: u64, expected_md5: &str) -> Result<FileState> {
+    compare_file(path, expected_size, expected_md5, || file_md5(path))
+}
+
+fn compare_file(
+    path: &Path,
+    expected_size: u64,
+    expected_md5: &str,
+    hash: impl FnOnce() -> Result<String>,
+) -> Result<FileState> {
+    if !path.is_file() {
+        return Ok(FileState::Missing);
+    }
+
+    if expected_md5.is_empty() {
+        return Ok(FileState::Ok);
+    }
+
+    let actual = path.metadata()?.len();
+    if actual != expected_size {
+        return Ok(FileState::SizeMismatch {
+            expected: expected_size,
+            actual,
+        });
+    }
+
+    let actual = hash()?;
+    if !actual.eq_ignore_ascii_case(expected_md5) {
+        return Ok(FileState::HashMismatch {
+            expected: expected_md5.to_string(),
+            actual,
+        });
+    }
+
+    Ok(FileState::Ok)
+}
+
+/// Like [`check_file`] for a file inside the game directory, going through its hash cache
+pub fn check_game_file(
+    game_path: &Path,
+    file: &ExpectedFile,
+    cache: &HashCache,
+) -> Result<FileState> {
+    compare_file(&game_path.join(file.name), file.size, file.md5, || {
+        cache.md5(file.name)
+    })
+}
+
+/// Checks all files in parallel and returns the ones that don't match, sorted by name.
+/// When `root` is the game directory, pass its hash cache to skip rehashing unchanged files
+pub fn check_files(
+    root: &Path,
+    files: &[ExpectedFile],
+    cache: Option<&HashCache>,
+    cancel: &CancelToken,
+    progress: &ProgressBar,
+) -> Result<Vec<(String, FileState)>> {
+    progress.set_length(files.len() as _);
+    progress.set_position(0);
+
+    let mut mismatches = files
+        .par_iter()
+        .map(|file| -> Result<Option<(String, FileState)>> {
+            cancel.check()?;
+            let state = match cache {
+                Some(cache) => check_game_file(root, file, cache)?,
+                None => check_file(&root.join(file.name), file.size, file.md5)?,
+            };
+
+            progress.inc(1);
+            Ok((!state.is_ok()).then(|| (file.name.to_string(), state)))
+        })
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+
+    mismatches.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(mismatches)
+}
+
+pub fn mismatch_report(what: &str, mismatches: &[(String, FileState)]) -> String {
+    let mut report = format!("{} {what}:", mismatches.len());
+    for (name, state) in mismatches {
+        report.push_str(&format!("\n  {name}: {state}"));
+    }
+    report
+}
+
+/// Like [`check_files`] but fails with a report listing every mismatch
+pub fn ensure_files(
+    root: &Path,
+    files: &[ExpectedFile],
+    cache: Option<&HashCache>,
+    cancel: &CancelToken,
+    progress: &ProgressBar,
+    what: &str,
+) -> Result<()> {
+    let mismatches = check_files(root, files, cache, cancel, progress)?;
+    if !mismatches.is_empty() {
+        bail!("{}", mismatch_report(what, &mismatches));
+    }
+
+    Ok(())
+}
@@ -17,6 +17,23 @@ pub enum SevenZipError {
         message: String,
     },
 
+    #[error("Listing '{archive}' failed (exit code {exit_code}): {message}")]
+    ListFailed {
+        archive: String,
+        exit_code: i32,
+        message: String,
+    },
+
+    #[error("Archive '{archive}' is corrupt (exit code {exit_code}): {message}")]
+    TestFailed {
+        archive: String,
+        exit_code: i32,
+        message: String,
+    },
+
+    #[error("Cancelled")]
+    Cancelled,
+
     #[error(transparent)]
     Other(#[from] anyhow::Error),
-}
\ No newline at end of file
+}
@@ -4,9 +4,15 @@ use std::{
     env,
     ffi::OsStr,
     fs,
+    io::Read,
     path::{Path, PathBuf},
-    process::{Command, Output},
-    sync::OnceLock,
+    process::{Command, Output, Stdio},
+    sync::{
+        OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::Duration,
 };
 
 use anyhow::Context;
@@ -19,6 +25,14 @@ static SEVENZ_INSTANCE: OnceLock<SevenZip> = OnceLock::new();
 
 pub struct SevenZip(PathBuf);
 
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    /// Path inside the archive, always separated by `/`
+    pub path: String,
+    pub size: u64,
+    pub is_dir: bool,
+}
+
 impl SevenZip {
     pub fn instance() -> Result<&'static Self> {
         SEVENZ_INSTANCE.get_or_try_init(Self::new)
@@ -72,7 +86,179 @@ impl SevenZip {
             .map_err(|e| SevenZipError::Execute(format!("Command failed: {}", e)))
     }
 
-    pub fn extract(archive_path: &Path, output_dir: &Path) -> Result<()> {
+    /// Like `execute`, but kills 7-Zip as soon as `cancel` is set
+    fn execute_cancellable(
+        &self,
+        args: &[impl AsRef<OsStr>],
+        cancel: &AtomicBool,
+    ) -> Result<Output> {
+        let mut child = Command::new(&self.0)
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| SevenZipError::Execute(format!("Command failed: {}", e)))?;
+
+        // Drained on their own threads so a full pipe can't stall 7-Zip
+        let read = |pipe: Option<Box<dyn Read + Send>>| {
+            thread::spawn(move || {
+                let mut data = Vec::new();
+                if let Some(mut pipe) = pipe {
+                    let _ = pipe.read_to_end(&mut data);
+                }
+                data
+            })
+        };
+        let stdout = read(child.stdout.take().map(|p| Box::new(p) as _));
+        let stderr = read(child.stderr.take().map(|p| Box::new(p) as _));
+
+        let status = loop {
+            if cancel.load(Ordering::Relaxed) {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(SevenZipError::Cancelled);
+            }
+
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) => thread::sleep(Duration::from_millis(50)),
+                Err(e) => return Err(SevenZipError::Execute(format!("Command failed: {}", e))),
+            }
+        };
+
+        Ok(Output {
+            status,
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        })
+    }
+
+    /// Checks the integrity of every file in the archive without extracting anything
+    pub fn test(archive_path: &Path, cancel: &AtomicBool) -> Result<()> {
+        if !archive_path.exists() {
+            return Err(SevenZipError::ArchiveNotFound(
+                archive_path.display().to_string(),
+            ));
+        }
+
+        let inst = Self::instance()?;
+
+        let args = ["t", &archive_path.display().to_string(), "-bsp0"];
+
+        let output = inst.execute_cancellable(&args, cancel)?;
+
+        if !output.status.success() {
+            // 7-Zip reports broken entries on stdout and only the summary on stderr
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            let message = if stderr.is_empty() {
+                String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .filter(|line| line.contains("ERROR") || line.contains("Error"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            } else {
+                stderr
+            };
+
+            return Err(SevenZipError::TestFailed {
+                archive: archive_path.display().to_string(),
+                exit_code: output.status.code().unwrap_or(-1),
+                message,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Lists every file and directory in the archive along with its unpacked size
+    pub fn list(archive_path: &Path) -> Result<Vec<ArchiveEntry>> {
+        if !archive_path.exists() {
+            return Err(SevenZipError::ArchiveNotFound(
+                archive_path.display().to_string(),
+            ));
+        }
+
+        let inst = Self::instance()?;
+
+        let args = ["l", &archive_path.display().to_string(), "-slt", "-ba"];
+
+        let output = inst.execute(&args)?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+
+            return Err(SevenZipError::ListFailed {
+                archive: archive_path.display().to_string(),
+                exit_code: output.status.code().unwrap_or(-1),
+                message: stderr,
+            });
+        }
+
+        // The technical listing is a block of `Key = Value` lines per entry
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut entries = Vec::new();
+        let mut current: Option<ArchiveEntry> = None;
+
+        for line in stdout.lines() {
+            let Some((key, value)) = line.split_once(" = ") else {
+                continue;
+            };
+
+            match key {
+                "Path" => {
+                    entries.extend(current.take());
+                    current = Some(ArchiveEntry {
+                        path: value.replace('\\', "/"),
+                        size: 0,
+                        is_dir: false,
+                    });
+                }
+                "Size" => {
+                    if let Some(entry) = current.as_mut() {
+                        entry.size = value.parse().unwrap_or(0);
+                    }
+                }
+                "Folder" | "Attributes" => {
+                    if let Some(entry) = current.as_mut() {
+                        entry.is_dir |= value == "+" || value.starts_with('D');
+                    }
+                }
+                _ => {}
+            }
+        }
+        entries.extend(current);
+
+        Ok(entries)
+    }
+
+    /// Extracts a single file from the archive into memory
+    pub fn read_file(archive_path: &Path, name: &str) -> Result<Vec<u8>> {
+        if !archive_path.exists() {
+            return Err(SevenZipError::ArchiveNotFound(
+                archive_path.display().to_string(),
+            ));
+        }
+
+        let inst = Self::instance()?;
+
+        let args = ["x", &archive_path.display().to_string(), "-so", name];
+
+        let output = inst.execute(&args)?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+
+            return Err(SevenZipError::ExtractionFailed {
+                archive: archive_path.display().to_string(),
+                exit_code: output.status.code().unwrap_or(-1),
+                message: stderr,
+            });
+        }
+
+        Ok(output.stdout)
+    }
+
+    pub fn extract(archive_path: &Path, output_dir: &Path, cancel: &AtomicBool) -> Result<()> {
         if !archive_path.exists() {
             return Err(SevenZipError::ArchiveNotFound(
                 archive_path.display().to_string(),
@@ -89,7 +275,7 @@ impl SevenZip {
             "-bsp0",
         ];
 
-        let output = inst.execute(&args)?;
+        let output = inst.execute_cancellable(&args, cancel)?;
 
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();